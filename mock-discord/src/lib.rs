@@ -0,0 +1,330 @@
+// Stand-in for the local Discord client's RPC websocket, for tests and offline development.
+// Accepts any client, goes through a fake AUTHORIZE / AUTHENTICATE handshake and then
+// plays back a Scenario of DISPATCH events. Also serves a token endpoint so the
+// code from AUTHORIZE can be exchanged without leaving the machine.
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+// Code handed out by AUTHORIZE, and the token the endpoint swaps it for
+pub const CODE: &str = "mock-code";
+pub const TOKEN: &str = "mock-token";
+
+// One event to send once the client has subscribed to it
+#[derive(Debug, Clone, Deserialize)]
+pub struct Step {
+    // Pause before sending, after the subscription arrives
+    #[serde(default)]
+    pub delay_ms: u64,
+    pub evt: String,
+    #[serde(default)]
+    pub data: Value,
+    // Change what GET_SELECTED_VOICE_CHANNEL answers before sending. `null` leaves the channel
+    #[serde(default, deserialize_with = "present")]
+    pub channel: Option<Value>,
+}
+
+// Keep an explicit `null` apart from a missing field
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scenario {
+    // Who AUTHENTICATE says we are
+    #[serde(default = "default_user")]
+    pub user: Value,
+    // Channel the user starts in, as GET_SELECTED_VOICE_CHANNEL returns it
+    #[serde(default)]
+    pub channel: Option<Value>,
+    // What GET_GUILDS lists
+    #[serde(default)]
+    pub guilds: Vec<Value>,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    // Turn AUTHORIZE down, as if the user cancelled Discord's prompt
+    #[serde(default)]
+    pub refuse_authorize: bool,
+}
+
+fn default_user() -> Value {
+    json!({ "id": "1", "username": "me", "avatar": null })
+}
+
+impl Default for Scenario {
+    fn default() -> Scenario {
+        Scenario {
+            user: default_user(),
+            channel: None,
+            guilds: vec![],
+            steps: vec![],
+            refuse_authorize: false,
+        }
+    }
+}
+
+// What clients have asked for so far, across every connection
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub connections: usize,
+    pub authorize: usize,
+    pub authenticate: usize,
+    // AUTHENTICATE attempts with a token other than TOKEN
+    pub rejected: usize,
+    // Events dropped with UNSUBSCRIBE, with the channel they were for
+    pub unsubscribed: Vec<(String, Option<String>)>,
+}
+
+pub struct MockDiscord {
+    pub port: u16,
+    pub token_url: String,
+    stats: Arc<Mutex<Stats>>,
+}
+
+type Writer = Arc<tokio::sync::Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>;
+
+impl MockDiscord {
+    // Listen on a free port
+    pub async fn start(scenario: Scenario) -> std::io::Result<MockDiscord> {
+        MockDiscord::start_on(scenario, 0).await
+    }
+
+    pub async fn start_on(scenario: Scenario, port: u16) -> std::io::Result<MockDiscord> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        let port = listener.local_addr()?.port();
+        let token_listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let token_url = format!("http://{}/token", token_listener.local_addr()?);
+        let stats = Arc::new(Mutex::new(Stats::default()));
+
+        tokio::spawn(serve_tokens(token_listener));
+        tokio::spawn({
+            let stats = stats.clone();
+            async move {
+                while let Ok((stream, _addr)) = listener.accept().await {
+                    tokio::spawn(serve_client(stream, scenario.clone(), stats.clone()));
+                }
+            }
+        });
+        Ok(MockDiscord {
+            port,
+            token_url,
+            stats,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+async fn send(writer: &Writer, packet: Value) {
+    let _ = writer
+        .lock()
+        .await
+        .send(Message::Text(packet.to_string()))
+        .await;
+}
+
+fn dispatch(evt: &str, data: Value) -> Value {
+    json!({ "cmd": "DISPATCH", "evt": evt, "data": data, "nonce": null })
+}
+
+fn error(cmd: &str, nonce: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "cmd": cmd,
+        "evt": "ERROR",
+        "data": { "code": code, "message": message },
+        "nonce": nonce,
+    })
+}
+
+fn voice_settings() -> Value {
+    let device = |volume: f64| {
+        json!({
+            "device_id": "default",
+            "volume": volume,
+            "available_devices": [{ "id": "default", "name": "Default" }],
+        })
+    };
+    json!({
+        "input": device(100.0),
+        "output": device(100.0),
+        "mode": {
+            "type": "VOICE_ACTIVITY",
+            "auto_threshold": true,
+            "threshold": -60.0,
+            "shortcut": [],
+            "delay": 20.0,
+        },
+        "automatic_gain_control": true,
+        "echo_cancellation": true,
+        "noise_suppression": true,
+        "qos": false,
+        "silence_warning": false,
+        "mute": false,
+        "deaf": false,
+    })
+}
+
+async fn serve_client(stream: TcpStream, scenario: Scenario, stats: Arc<Mutex<Stats>>) {
+    let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(_err) => return,
+    };
+    stats.lock().unwrap().connections += 1;
+    let (write, mut read) = ws_stream.split();
+    let writer: Writer = Arc::new(tokio::sync::Mutex::new(write));
+    let channel = Arc::new(Mutex::new(scenario.channel.clone()));
+    let (subscribed_sender, subscribed) = watch::channel(HashSet::<String>::new());
+
+    send(
+        &writer,
+        dispatch("READY", json!({ "v": 1, "user": scenario.user })),
+    )
+    .await;
+
+    // Play back each step once its event is subscribed to
+    let player = tokio::spawn({
+        let writer = writer.clone();
+        let channel = channel.clone();
+        let steps = scenario.steps.clone();
+        let mut subscribed = subscribed.clone();
+        async move {
+            for step in steps {
+                if subscribed
+                    .wait_for(|subscribed| subscribed.contains(&step.evt))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                sleep(Duration::from_millis(step.delay_ms)).await;
+                if let Some(new_channel) = step.channel {
+                    *channel.lock().unwrap() = match new_channel {
+                        Value::Null => None,
+                        new_channel => Some(new_channel),
+                    };
+                }
+                send(&writer, dispatch(&step.evt, step.data)).await;
+            }
+        }
+    });
+
+    while let Some(Ok(message)) = read.next().await {
+        let raw = match message {
+            Message::Text(raw) => raw,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let packet: Value = match serde_json::from_str(&raw) {
+            Ok(packet) => packet,
+            Err(_err) => continue,
+        };
+        let cmd = packet["cmd"].as_str().unwrap_or_default().to_string();
+        let nonce = packet["nonce"].clone();
+        let args = &packet["args"];
+        let reply = |data: Value| json!({ "cmd": cmd, "data": data, "evt": null, "nonce": nonce });
+        let answer = match cmd.as_str() {
+            "AUTHORIZE" => {
+                stats.lock().unwrap().authorize += 1;
+                if scenario.refuse_authorize {
+                    error(&cmd, &nonce, 5000, "OAuth2 Error: access_denied")
+                } else {
+                    reply(json!({ "code": CODE }))
+                }
+            }
+            "AUTHENTICATE" => {
+                stats.lock().unwrap().authenticate += 1;
+                if args["access_token"] == TOKEN {
+                    reply(json!({
+                        "access_token": TOKEN,
+                        "user": scenario.user,
+                        "scopes": ["rpc", "messages.read", "rpc.notifications.read"],
+                    }))
+                } else {
+                    stats.lock().unwrap().rejected += 1;
+                    error(&cmd, &nonce, 4009, "Invalid access token")
+                }
+            }
+            "GET_GUILDS" => reply(json!({ "guilds": scenario.guilds })),
+            "GET_SELECTED_VOICE_CHANNEL" => reply(channel.lock().unwrap().clone().into()),
+            "GET_VOICE_SETTINGS" | "SET_VOICE_SETTINGS" => reply(voice_settings()),
+            "SUBSCRIBE" => {
+                let evt = packet["evt"].as_str().unwrap_or_default().to_string();
+                subscribed_sender.send_modify(|subscribed| {
+                    subscribed.insert(evt.clone());
+                });
+                reply(json!({ "evt": evt }))
+            }
+            "UNSUBSCRIBE" => {
+                let evt = packet["evt"].as_str().unwrap_or_default().to_string();
+                let channel_id = args["channel_id"].as_str().map(str::to_string);
+                stats
+                    .lock()
+                    .unwrap()
+                    .unsubscribed
+                    .push((evt.clone(), channel_id));
+                reply(json!({ "evt": evt }))
+            }
+            _ => error(&cmd, &nonce, 4000, "Unknown command"),
+        };
+        send(&writer, answer).await;
+    }
+    player.abort();
+}
+
+// Minimal HTTP endpoint answering every POST with TOKEN, if it carries CODE
+async fn serve_tokens(listener: TcpListener) {
+    while let Ok((mut stream, _addr)) = listener.accept().await {
+        tokio::spawn(async move {
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            // Read headers, then as much body as they announce
+            loop {
+                let read = match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => read,
+                };
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+            }
+            let text = String::from_utf8_lossy(&request);
+            let (status, body) = match text.contains(CODE) {
+                true => ("200 OK", json!({ "access_token": TOKEN })),
+                false => ("400 Bad Request", json!({ "error": "invalid_grant" })),
+            };
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -0,0 +1,56 @@
+// Run the mock Discord on its own, e.g. to develop an overlay without Discord:
+//   discern-mock-discord --port 6463 scenarios/speaking.json
+//   DISCERN_RPC_PORT=6463 DISCERN_TOKEN_URL=<printed url> discern-x11
+use discern_mock_discord::{MockDiscord, Scenario};
+use std::env;
+use std::fs;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("Usage: discern-mock-discord [--port PORT] [SCENARIO.json]");
+    exit(2);
+}
+
+#[tokio::main]
+async fn main() {
+    let mut port = 0;
+    let mut scenario = Scenario::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" | "--port" => {
+                port = match args.next().and_then(|port| port.parse().ok()) {
+                    Some(port) => port,
+                    None => usage(),
+                }
+            }
+            "-h" | "--help" => usage(),
+            path => {
+                let raw = fs::read_to_string(path).unwrap_or_else(|err| {
+                    eprintln!("Unable to read {}: {}", path, err);
+                    exit(1);
+                });
+                scenario = serde_json::from_str(&raw).unwrap_or_else(|err| {
+                    eprintln!("Unable to parse {}: {}", path, err);
+                    exit(1);
+                });
+            }
+        }
+    }
+
+    let mock = MockDiscord::start_on(scenario, port)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("Unable to listen: {}", err);
+            exit(1);
+        });
+    println!("RPC websocket on {}", mock.url());
+    println!("Token endpoint on {}", mock.token_url);
+
+    let _ = tokio::signal::ctrl_c().await;
+    let stats = mock.stats();
+    println!(
+        "{} connections, {} AUTHORIZE, {} AUTHENTICATE ({} rejected)",
+        stats.connections, stats.authorize, stats.authenticate, stats.rejected
+    );
+}
@@ -19,50 +19,60 @@ pub async fn avatar_downloader(
         let mut already_done: HashMap<String, Option<Bytes>> = HashMap::new();
 
         while let Some(state) = recvr.next().await {
-            for (_key, value) in state.users.into_iter() {
-                if value.avatar.is_some() {
-                    let avatar_key: String = format!("{}/{}", value.id, value.avatar.unwrap());
-                    if !already_done.contains_key(&avatar_key) {
-                        println!("Requesting {}", avatar_key);
-                        let url = format!("https://cdn.discordapp.com/avatars/{}.png", avatar_key);
-                        match reqwest::Client::new()
-                            .get(url)
-                            .header("Referer", "https://streamkit.discord.com/overlay/voice")
-                            .header("User-Agent", "Mozilla/5.0")
-                            .send()
-                            .await
-                        {
-                            Ok(resp) => match resp.bytes().await {
-                                Ok(bytes) => {
-                                    already_done.insert(avatar_key.clone(), Some(bytes.clone()));
-                                    match sender
-                                        .send(DiscordAvatarRaw {
-                                            key: avatar_key.clone(),
-                                            raw: Some(bytes.clone()),
-                                        })
-                                        .await
-                                    {
-                                        Ok(_v) => {}
-                                        Err(_e) => {}
-                                    }
+            // Key the frontends look the image up by, and where to fetch it
+            let mut wanted: Vec<(String, String)> = vec![];
+            for (_key, value) in state.users.iter() {
+                if let Some(avatar) = &value.avatar {
+                    let avatar_key = format!("{}/{}", value.id, avatar);
+                    let url = format!("https://cdn.discordapp.com/avatars/{}.png", avatar_key);
+                    wanted.push((avatar_key, url));
+                }
+            }
+            // Only the icon of the guild we're in is worth fetching
+            if let Some(icon_key) = state.guild().and_then(|guild| guild.icon_key()) {
+                let url = format!("https://cdn.discordapp.com/{}.png", icon_key);
+                wanted.push((icon_key, url));
+            }
+            for (avatar_key, url) in wanted {
+                if !already_done.contains_key(&avatar_key) {
+                    println!("Requesting {}", avatar_key);
+                    match reqwest::Client::new()
+                        .get(url)
+                        .header("Referer", "https://streamkit.discord.com/overlay/voice")
+                        .header("User-Agent", "Mozilla/5.0")
+                        .send()
+                        .await
+                    {
+                        Ok(resp) => match resp.bytes().await {
+                            Ok(bytes) => {
+                                already_done.insert(avatar_key.clone(), Some(bytes.clone()));
+                                match sender
+                                    .send(DiscordAvatarRaw {
+                                        key: avatar_key.clone(),
+                                        raw: Some(bytes.clone()),
+                                    })
+                                    .await
+                                {
+                                    Ok(_v) => {}
+                                    Err(_e) => {}
                                 }
-                                Err(_err) => {
-                                    already_done.insert(avatar_key.clone(), None);
-                                    match sender
-                                        .send(DiscordAvatarRaw {
-                                            key: avatar_key.clone(),
-                                            raw: None,
-                                        })
-                                        .await
-                                    {
-                                        Ok(_v) => {}
-                                        Err(_e) => {}
-                                    }
+                            }
+                            Err(_err) => {
+                                already_done.insert(avatar_key.clone(), None);
+                                match sender
+                                    .send(DiscordAvatarRaw {
+                                        key: avatar_key.clone(),
+                                        raw: None,
+                                    })
+                                    .await
+                                {
+                                    Ok(_v) => {}
+                                    Err(_e) => {}
                                 }
-                            },
-                            Err(err) => {
-                                println!("{}", err);
                             }
+                        },
+                        Err(err) => {
+                            println!("{}", err);
                         }
                     }
                 }
@@ -0,0 +1,384 @@
+// Matches replies from Discord to the command that caused them
+use crate::data::{ConnectionHealth, StateEvent};
+use crate::protocol::{
+    ChannelData, ChannelSummary, Command, ErrorData, Guild, Incoming, Pan, Payload, Request,
+    Response, UserVoiceSettings, VoiceMode, VoiceSettings, VoiceStateEntry,
+};
+use futures::channel::{mpsc, oneshot};
+use futures::lock::Mutex;
+use futures_util::SinkExt;
+use serde_json::Value;
+use std::collections::hash_map::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{timeout, Duration};
+
+// How long to wait for Discord to answer a single command
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub enum CommandError {
+    // Discord answered with an ERROR event
+    Discord(ErrorData),
+    // No answer within COMMAND_TIMEOUT
+    Timeout,
+    // The connection went away before an answer arrived
+    Disconnected,
+    // Discord answered with something other than what the command expects
+    Unexpected(Box<Response>),
+    // Discord answered, but the reply could not be decoded
+    Unreadable(String),
+    // The thing asked about does not exist, or is not where we looked
+    NotFound(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Discord(error) => write!(f, "{} ({})", error.message, error.code),
+            CommandError::Timeout => write!(f, "Timed out waiting for Discord"),
+            CommandError::Disconnected => write!(f, "Not connected to Discord"),
+            CommandError::Unexpected(response) => write!(f, "Unexpected reply {:?}", response),
+            CommandError::Unreadable(err) => write!(f, "Unreadable reply: {}", err),
+            CommandError::NotFound(what) => write!(f, "{} not found", what),
+        }
+    }
+}
+
+pub type CommandResult = Result<Response, CommandError>;
+
+// Requests still waiting on a reply, keyed by nonce
+pub type PendingTable = Arc<Mutex<HashMap<String, oneshot::Sender<CommandResult>>>>;
+
+#[derive(Clone)]
+pub struct CommandClient {
+    outgoing: mpsc::Sender<Request>,
+    pending: PendingTable,
+}
+
+impl CommandClient {
+    pub fn new(outgoing: mpsc::Sender<Request>) -> CommandClient {
+        CommandClient {
+            outgoing,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn pending(&self) -> PendingTable {
+        self.pending.clone()
+    }
+
+    // Send a command and wait for the reply carrying the same nonce
+    pub async fn request(&self, command: Command) -> CommandResult {
+        let packet = Request::new(command);
+        let (waiter, reply) = oneshot::channel();
+        self.pending
+            .lock()
+            .await
+            .insert(packet.nonce.clone(), waiter);
+
+        // Queueing counts towards the timeout too, as nothing is sent before authentication
+        let exchange = async {
+            let mut outgoing = self.outgoing.clone();
+            if outgoing.send(packet).await.is_err() {
+                return Err(CommandError::Disconnected);
+            }
+            reply.await.unwrap_or(Err(CommandError::Disconnected))
+        };
+        let result = match timeout(COMMAND_TIMEOUT, exchange).await {
+            Ok(result) => result,
+            Err(_elapsed) => Err(CommandError::Timeout),
+        };
+        if result.is_err() {
+            expire(&self.pending).await;
+        }
+        result
+    }
+}
+
+// Forget requests nobody waits for any more, because they timed out or whoever sent
+// them gave up. The one place expired requests are cleaned up
+pub async fn expire(pending: &PendingTable) {
+    pending
+        .lock()
+        .await
+        .retain(|_nonce, waiter| !waiter.is_canceled());
+}
+
+// Pass a reply on to whoever is waiting for its nonce, if anyone
+pub async fn resolve(pending: &PendingTable, packet: &Incoming) {
+    let result = match &packet.payload {
+        Payload::Response(response) => Ok(response.clone()),
+        Payload::Error { error, .. } => Err(CommandError::Discord(error.clone())),
+        Payload::Event(_) => return,
+    };
+    let nonce = match &packet.nonce {
+        Some(nonce) => nonce,
+        None => return,
+    };
+    if let Some(waiter) = pending.lock().await.remove(nonce) {
+        let _ = waiter.send(result);
+    }
+}
+
+// A reply that failed to parse still ends the request its nonce belongs to,
+// rather than leaving it to time out
+pub async fn resolve_unreadable(pending: &PendingTable, raw: &str, err: &serde_json::Error) {
+    let nonce = match serde_json::from_str::<Value>(raw) {
+        Ok(frame) => frame["nonce"].as_str().map(str::to_string),
+        Err(_) => None,
+    };
+    let nonce = match nonce {
+        Some(nonce) => nonce,
+        None => return,
+    };
+    if let Some(waiter) = pending.lock().await.remove(&nonce) {
+        let _ = waiter.send(Err(CommandError::Unreadable(err.to_string())));
+    }
+}
+
+// Whether anyone still waits on this request. Ones that expired while queued
+// are not worth sending
+pub async fn still_pending(pending: &PendingTable, packet: &Request) -> bool {
+    expire(pending).await;
+    pending.lock().await.contains_key(&packet.nonce)
+}
+
+// Everyone following StateEvents. Unbounded so a slow reader never misses one,
+// receivers that have gone away are dropped on the next send
+pub type EventSubscribers = Arc<std::sync::Mutex<Vec<mpsc::UnboundedSender<StateEvent>>>>;
+
+pub fn broadcast(subscribers: &EventSubscribers, events: &[StateEvent]) {
+    if events.is_empty() {
+        return;
+    }
+    subscribers.lock().unwrap().retain(|subscriber| {
+        events
+            .iter()
+            .all(|event| subscriber.unbounded_send(event.clone()).is_ok())
+    });
+}
+
+// Control surface handed out by core::connector. Commands are held back until
+// the connection is authenticated, then sent in order unless they expired meanwhile
+#[derive(Clone)]
+pub struct DiscordClient {
+    commands: CommandClient,
+    health: watch::Receiver<ConnectionHealth>,
+    events: EventSubscribers,
+}
+
+#[allow(dead_code)]
+impl DiscordClient {
+    pub fn new(
+        commands: CommandClient,
+        health: watch::Receiver<ConnectionHealth>,
+        events: EventSubscribers,
+    ) -> DiscordClient {
+        DiscordClient {
+            commands,
+            health,
+            events,
+        }
+    }
+
+    // Follow the connection phase and voice link quality. Opt in, separate
+    // from the ConnState updates
+    pub fn health(&self) -> watch::Receiver<ConnectionHealth> {
+        self.health.clone()
+    }
+
+    // Every change to the state from now on, in order. Each state is sent to the
+    // ConnState channel before the events that lead to it
+    pub fn events(&self) -> mpsc::UnboundedReceiver<StateEvent> {
+        let (sender, recv) = mpsc::unbounded();
+        self.events.lock().unwrap().push(sender);
+        recv
+    }
+
+    // Wait for the connector to authenticate with Discord
+    pub async fn wait_ready(&self, limit: Duration) -> Result<(), CommandError> {
+        let mut health = self.health.clone();
+        let result = timeout(limit, health.wait_for(|health| health.phase.is_ready())).await;
+        match result {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_closed)) => Err(CommandError::Disconnected),
+            Err(_elapsed) => Err(CommandError::Timeout),
+        }
+    }
+
+    pub async fn request(&self, command: Command) -> CommandResult {
+        self.commands.request(command).await
+    }
+
+    pub async fn get_selected_voice_channel(&self) -> Result<Option<ChannelData>, CommandError> {
+        match self.request(Command::GetSelectedVoiceChannel {}).await? {
+            Response::GetSelectedVoiceChannel(channel) => Ok(channel),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    pub async fn select_voice_channel(
+        &self,
+        channel_id: &str,
+    ) -> Result<Option<ChannelData>, CommandError> {
+        let command = Command::SelectVoiceChannel {
+            channel_id: channel_id.to_string(),
+            force: true,
+        };
+        match self.request(command).await? {
+            Response::SelectVoiceChannel(channel) => Ok(channel),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    pub async fn get_voice_settings(&self) -> Result<VoiceSettings, CommandError> {
+        match self.request(Command::GetVoiceSettings {}).await? {
+            Response::GetVoiceSettings(settings) => Ok(settings),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    async fn set_voice_settings(
+        &self,
+        args: serde_json::Value,
+    ) -> Result<VoiceSettings, CommandError> {
+        match self.request(Command::SetVoiceSettings(args)).await? {
+            Response::SetVoiceSettings(settings) => Ok(settings),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    pub async fn set_mute(&self, mute: bool) -> Result<VoiceSettings, CommandError> {
+        self.set_voice_settings(serde_json::json!({ "mute": mute }))
+            .await
+    }
+
+    pub async fn set_deaf(&self, deaf: bool) -> Result<VoiceSettings, CommandError> {
+        self.set_voice_settings(serde_json::json!({ "deaf": deaf }))
+            .await
+    }
+
+    pub async fn set_input_device(&self, device_id: &str) -> Result<VoiceSettings, CommandError> {
+        self.set_voice_settings(serde_json::json!({ "input": { "device_id": device_id } }))
+            .await
+    }
+
+    pub async fn set_output_device(&self, device_id: &str) -> Result<VoiceSettings, CommandError> {
+        self.set_voice_settings(serde_json::json!({ "output": { "device_id": device_id } }))
+            .await
+    }
+
+    // Percent, 0 - 100
+    pub async fn set_input_volume(&self, volume: f64) -> Result<VoiceSettings, CommandError> {
+        self.set_voice_settings(serde_json::json!({ "input": { "volume": volume } }))
+            .await
+    }
+
+    // Percent, 0 - 200
+    pub async fn set_output_volume(&self, volume: f64) -> Result<VoiceSettings, CommandError> {
+        self.set_voice_settings(serde_json::json!({ "output": { "volume": volume } }))
+            .await
+    }
+
+    pub async fn set_voice_mode(&self, mode: VoiceMode) -> Result<VoiceSettings, CommandError> {
+        self.set_voice_settings(serde_json::json!({ "mode": mode }))
+            .await
+    }
+
+    pub async fn get_guilds(&self) -> Result<Vec<Guild>, CommandError> {
+        match self.request(Command::GetGuilds {}).await? {
+            Response::GetGuilds(list) => Ok(list.guilds),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    pub async fn get_channels(&self, guild_id: &str) -> Result<Vec<ChannelSummary>, CommandError> {
+        let command = Command::GetChannels {
+            guild_id: guild_id.to_string(),
+        };
+        match self.request(command).await? {
+            Response::GetChannels(list) => Ok(list.channels),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    pub async fn get_channel(&self, channel_id: &str) -> Result<ChannelData, CommandError> {
+        let command = Command::GetChannel {
+            channel_id: channel_id.to_string(),
+        };
+        match self.request(command).await? {
+            Response::GetChannel(channel) => Ok(channel),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    // Voice state of another user in our current channel
+    pub async fn get_user_voice_state(
+        &self,
+        user_id: &str,
+    ) -> Result<VoiceStateEntry, CommandError> {
+        self.get_selected_voice_channel()
+            .await?
+            .and_then(|channel| {
+                channel
+                    .voice_states
+                    .into_iter()
+                    .find(|voice_state| voice_state.user.id == user_id)
+            })
+            .ok_or_else(|| CommandError::NotFound(format!("User {} in current channel", user_id)))
+    }
+
+    pub async fn set_user_voice_settings(
+        &self,
+        settings: UserVoiceSettings,
+    ) -> Result<UserVoiceSettings, CommandError> {
+        match self
+            .request(Command::SetUserVoiceSettings(settings))
+            .await?
+        {
+            Response::SetUserVoiceSettings(settings) => Ok(settings),
+            other => Err(CommandError::Unexpected(Box::new(other))),
+        }
+    }
+
+    pub async fn set_user_volume(
+        &self,
+        user_id: &str,
+        volume: f64,
+    ) -> Result<UserVoiceSettings, CommandError> {
+        self.set_user_voice_settings(UserVoiceSettings {
+            user_id: user_id.to_string(),
+            volume: Some(volume),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn set_user_mute(
+        &self,
+        user_id: &str,
+        mute: bool,
+    ) -> Result<UserVoiceSettings, CommandError> {
+        self.set_user_voice_settings(UserVoiceSettings {
+            user_id: user_id.to_string(),
+            mute: Some(mute),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn set_user_pan(
+        &self,
+        user_id: &str,
+        pan: Pan,
+    ) -> Result<UserVoiceSettings, CommandError> {
+        self.set_user_voice_settings(UserVoiceSettings {
+            user_id: user_id.to_string(),
+            pan: Some(pan),
+            ..Default::default()
+        })
+        .await
+    }
+}
@@ -1,31 +1,61 @@
 extern crate clap;
 extern crate serde_json;
+use clap::{arg, command};
 use futures::lock::Mutex;
 use futures::stream::StreamExt;
+use std::path::Path;
+use std::process::exit;
 use std::sync::Arc;
 
+mod client;
 mod core;
 mod data;
+mod endpoint;
 mod macros;
+mod protocol;
+mod recording;
+mod token;
 
+use crate::core::ConnectorConfig;
 use crate::data::ConnState;
+use crate::recording::Recorder;
 
 #[tokio::main]
 async fn main() {
+    let matches = command!()
+        .arg(recording::replay_arg())
+        .arg(
+            arg!(--record <FILE> "Write every frame to and from Discord to FILE as NDJSON, for --replay")
+                .required(false),
+        )
+        .get_matches();
+    let mut config = ConnectorConfig::from_args(&matches);
+    if let Some(path) = matches.value_of("record") {
+        match Recorder::create(Path::new(path)) {
+            Ok(recorder) => config.recorder = Some(recorder),
+            Err(err) => {
+                eprintln!("Unable to record to {}: {}", path, err);
+                exit(1);
+            }
+        }
+    }
+
     // Websocket events to main thread
     let (event_sender, event_recv) = futures::channel::mpsc::channel::<ConnState>(10);
     let event_sender = Arc::new(Mutex::new(event_sender));
     let event_recv = Arc::new(Mutex::new(event_recv));
 
-    // Main thread messages to Websocket output
-    let (msg_sender, msg_recv) = futures::channel::mpsc::channel::<String>(10);
-    let _msg_sender = Arc::new(Mutex::new(msg_sender));
-    let msg_recv = Arc::new(Mutex::new(msg_recv));
-
     // Start a thread for connection
     let connector_event_sender = event_sender.clone();
-    let connector_msg_recv = msg_recv.clone();
-    core::connector(connector_event_sender.clone(), connector_msg_recv.clone()).await;
+    let discord = core::connector_with(connector_event_sender.clone(), config).await;
+
+    // Report each step of connecting and every voice ping alongside the state
+    let mut health = discord.health();
+    tokio::spawn(async move {
+        while health.changed().await.is_ok() {
+            println!("{:?}", *health.borrow());
+        }
+    });
 
     // Start our own loop - just print it
     let mut state = ConnState::new();
@@ -1,302 +1,1048 @@
 extern crate clap;
 extern crate serde_json;
 use futures::lock::Mutex;
+use futures::stream::SplitSink;
+use futures::Stream;
 use futures_util::{SinkExt, StreamExt};
 use http::Request;
-use serde_json::json;
-use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Notify};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tokio_tungstenite::{
+    connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+};
 use tungstenite::handshake::client::generate_key;
 
+use crate::client::EventSubscribers;
+use crate::data::{ConnectionHealth, ConnectionPhase, StateEvent};
+use crate::endpoint::Endpoint;
+use crate::protocol::{Event, Payload, Response};
+use crate::recording::{Direction, Frame, Recorder};
+use crate::token::{exchanger_from_env, ReplayExchanger, TokenCache, TokenExchanger};
 use crate::*;
 
+// Delay before the first retry, doubled after each failure up to BACKOFF_MAX
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+// Times to go back through AUTHORIZE after Discord rejects our token on one connection
+const MAX_REAUTHORIZE: u32 = 3;
+// States kept for a frontend that isn't keeping up, past this only the newest count
+const STATE_BACKLOG: usize = 10;
+
+// Things that go wrong while handling Discord's traffic. None of them end the connector,
+// the packet or event at fault is logged and skipped
+#[derive(Debug)]
+pub enum ConnectorError {
+    // Packet that does not match the protocol
+    Protocol(serde_json::Error),
+    // Event about a user not in our current channel
+    UnknownUser(String),
+    // Event that only makes sense once we know who we are
+    NotAuthenticated,
+    // Endpoint settings that don't make a valid websocket request
+    InvalidEndpoint(http::Error),
+    // Writing to Discord failed
+    Socket(tungstenite::Error),
+}
+
+impl fmt::Display for ConnectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectorError::Protocol(err) => write!(f, "Unable to parse packet: {}", err),
+            ConnectorError::UnknownUser(user_id) => write!(f, "Unknown user {}", user_id),
+            ConnectorError::NotAuthenticated => write!(f, "Not authenticated yet"),
+            ConnectorError::InvalidEndpoint(err) => write!(f, "Invalid endpoint: {}", err),
+            ConnectorError::Socket(err) => write!(f, "Unable to send to Discord: {}", err),
+        }
+    }
+}
+
+fn skip_on_error(debug_stdout: bool, result: Result<(), ConnectorError>) {
+    if let Err(err) = result {
+        if debug_stdout {
+            eprintln!("Skipping event: {}", err);
+        }
+    }
+}
+
+// Jittered exponential backoff between connection attempts
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff { attempt: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let ceiling = BACKOFF_BASE
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(BACKOFF_MAX);
+        self.attempt += 1;
+        // Somewhere between half and all of the ceiling, so several clients don't retry in step
+        let jitter = (RandomState::new().build_hasher().finish() % 1000) as f64 / 1000.0;
+        ceiling / 2 + (ceiling / 2).mul_f64(jitter)
+    }
+}
+
 async fn user_left_channel(state: Arc<Mutex<data::ConnState>>) {
     let mut current_state = state.lock().await;
     current_state.voice_channel = None;
+    current_state.channel = None;
     current_state.users.clear();
     current_state.voice_states.clear();
+    if let Some(self_user) = &mut current_state.self_user {
+        self_user.speaking = false;
+    }
 }
 
-async fn set_user_talking(state: Arc<Mutex<data::ConnState>>, user_id: String, talking: bool) {
-    let mut unlocked = state.lock().await;
-    let mut voice_state = unlocked.voice_states.get_mut(&user_id).unwrap().clone();
-    voice_state.talking = talking;
-    unlocked.voice_states.insert(user_id.clone(), voice_state);
+// Switch to the text channel we should be following, dropping the old one's messages.
+// Gives the channels to unsubscribe from and subscribe to if it changed. Waits until we're authenticated
+fn follow_text_channel(
+    current_state: &mut data::ConnState,
+    text_channel: &TextChannel,
+) -> Option<(Option<String>, Option<String>)> {
+    current_state.self_user.as_ref()?;
+    let wanted = match text_channel {
+        TextChannel::Voice => current_state.voice_channel.clone(),
+        TextChannel::Channel(channel_id) => Some(channel_id.clone()),
+        TextChannel::Off => None,
+    };
+    if wanted == current_state.text_channel {
+        return None;
+    }
+    let previous = std::mem::replace(&mut current_state.text_channel, wanted.clone());
+    current_state.messages.clear();
+    Some((previous, wanted))
 }
 
-async fn update_state_from_voice_state(state: Arc<Mutex<data::ConnState>>, voice_state: &Value) {
-    let user_id: String = voice_state["user"]["id"].as_str().unwrap().to_string();
-    let mut current_state = state.lock().await;
+fn text_message(message: &protocol::Message, received: u64) -> data::TextMessageData {
+    let author = message
+        .nick
+        .clone()
+        .or_else(|| {
+            message
+                .author
+                .as_ref()
+                .map(|author| author.username.clone())
+        })
+        .unwrap_or_default();
+    data::TextMessageData {
+        id: message.id.clone(),
+        author_id: message.author.as_ref().map(|author| author.id.clone()),
+        author,
+        content: message.content.clone(),
+        embeds: message
+            .embeds
+            .iter()
+            .map(|embed| data::EmbedData {
+                kind: embed.kind.clone(),
+                title: embed.title.clone(),
+                description: embed.description.clone(),
+                url: embed.url.clone(),
+            })
+            .collect(),
+        attachments: message
+            .attachments
+            .iter()
+            .map(|attachment| data::AttachmentData {
+                filename: attachment.filename.clone(),
+                size: attachment.size,
+                url: attachment.url.clone(),
+            })
+            .collect(),
+        received,
+    }
+}
 
-    let username = voice_state["user"]["username"]
-        .as_str()
-        .unwrap()
-        .to_string();
-    let mut avatar: Option<String> = None;
-    match voice_state["user"]["avatar"].as_str() {
-        Some(in_avatar) => {
-            avatar = Some(in_avatar.to_string());
+// Keep the buffer in step with MESSAGE_* events from the channel we follow.
+// Subscriptions to channels we've since left may still deliver, those are ignored
+async fn update_state_from_message(state: Arc<Mutex<data::ConnState>>, event: &protocol::Event) {
+    let mut current_state = state.lock().await;
+    let follows = |current_state: &data::ConnState, event: &protocol::MessageEventData| {
+        current_state.text_channel.as_ref() == Some(&event.channel_id)
+    };
+    match event {
+        Event::MessageCreate(created) if follows(&current_state, created) => {
+            let messages = &mut current_state.messages;
+            messages.push_back(text_message(&created.message, data::now_ms()));
+            while messages.len() > data::MESSAGE_LIMIT {
+                messages.pop_front();
+            }
+        }
+        Event::MessageUpdate(updated) if follows(&current_state, updated) => {
+            let messages = &mut current_state.messages;
+            if let Some(known) = messages
+                .iter_mut()
+                .find(|known| known.id == updated.message.id)
+            {
+                *known = text_message(&updated.message, known.received);
+            }
         }
-        None => {}
+        Event::MessageDelete(deleted) if follows(&current_state, deleted) => {
+            current_state
+                .messages
+                .retain(|known| known.id != deleted.message.id);
+        }
+        _ => {}
     }
+}
 
-    let user = data::DiscordUserData {
-        avatar: avatar,
-        id: user_id.clone(),
-        username: username,
+// Someone else left the channel
+async fn user_left(state: Arc<Mutex<data::ConnState>>, user_id: &str) {
+    let mut current_state = state.lock().await;
+    current_state.users.remove(user_id);
+    current_state.voice_states.remove(user_id);
+}
+
+// Who AUTHENTICATE says we are. Mute and deafen come from voice settings, which may
+// have arrived on an earlier connection
+async fn update_state_from_self(state: Arc<Mutex<data::ConnState>>, user: &protocol::User) {
+    let mut current_state = state.lock().await;
+    let (mute, deaf) = match &current_state.voice_settings {
+        Some(settings) => (settings.mute, settings.deaf),
+        None => (false, false),
     };
-    current_state.users.insert(user_id.clone(), user);
-    let mut nick: Option<String> = None;
-    match voice_state["nick"].as_str() {
-        Some(st) => {
-            nick = Some(st.to_string());
+    current_state.user_id = Some(user.id.clone());
+    current_state.self_user = Some(data::SelfUserData {
+        user: data::DiscordUserData {
+            avatar: user.avatar.clone(),
+            id: user.id.clone(),
+            username: user.username.clone(),
+        },
+        mute,
+        deaf,
+        speaking: false,
+    });
+}
+
+async fn set_user_talking(
+    state: Arc<Mutex<data::ConnState>>,
+    user_id: String,
+    talking: bool,
+) -> Result<(), ConnectorError> {
+    let mut unlocked = state.lock().await;
+    let tick = unlocked.next_tick();
+    if unlocked.is_self(&user_id) {
+        if let Some(self_user) = &mut unlocked.self_user {
+            self_user.speaking = talking;
         }
-        None => {}
     }
-    match voice_state["voice_state"]["nick"].as_str() {
-        Some(st) => {
-            nick = Some(st.to_string());
+    match unlocked.voice_states.get_mut(&user_id) {
+        Some(voice_state) => {
+            voice_state.talking = talking;
+            if talking {
+                voice_state.last_spoke = tick;
+            }
+            Ok(())
         }
-        None => {}
+        None => Err(ConnectorError::UnknownUser(user_id)),
     }
-    let mut talking = false;
-    if current_state.voice_states.contains_key(&user_id.clone()) {
-        talking = current_state
-            .voice_states
-            .get(&user_id.clone())
-            .unwrap()
-            .talking;
+}
+
+async fn update_state_from_user_voice_settings(
+    state: Arc<Mutex<data::ConnState>>,
+    settings: &protocol::UserVoiceSettings,
+) {
+    let mut current_state = state.lock().await;
+    if let Some(voice_state) = current_state.voice_states.get_mut(&settings.user_id) {
+        if let Some(mute) = settings.mute {
+            voice_state.local_mute = mute;
+        }
+        if let Some(volume) = settings.volume {
+            voice_state.volume = volume.round() as u32;
+        }
     }
+}
+
+async fn update_state_from_voice_settings(
+    state: Arc<Mutex<data::ConnState>>,
+    settings: &protocol::VoiceSettings,
+) {
+    let mut current_state = state.lock().await;
+    if let Some(self_user) = &mut current_state.self_user {
+        self_user.mute = settings.mute;
+        self_user.deaf = settings.deaf;
+    }
+    current_state.voice_settings = Some(data::VoiceSettingsData {
+        input_device: settings.input.device_id.clone(),
+        output_device: settings.output.device_id.clone(),
+        input_volume: settings.input.volume.round() as u32,
+        output_volume: settings.output.volume.round() as u32,
+        mode: settings.mode.kind.clone(),
+        noise_suppression: settings.noise_suppression,
+        echo_cancellation: settings.echo_cancellation,
+        automatic_gain_control: settings.automatic_gain_control,
+        mute: settings.mute,
+        deaf: settings.deaf,
+    });
+}
+
+// Keep the details of our channel. Guild names come from GET_GUILDS, which may
+// answer before or after the channel does
+fn update_state_from_channel(current_state: &mut data::ConnState, channel: &protocol::ChannelData) {
+    current_state.channel = Some(data::VoiceChannelData {
+        id: channel.id.clone(),
+        name: channel.name.clone(),
+        guild_id: channel.guild_id.clone(),
+        guild_name: None,
+        kind: channel.kind,
+        bitrate: channel.bitrate,
+        user_limit: channel.user_limit,
+    });
+    refresh_guild_name(current_state);
+}
+
+// Add or replace one guild, keeping the icon if Discord left it out
+fn update_state_from_guild(current_state: &mut data::ConnState, guild: &protocol::Guild) {
+    let icon = guild.icon_hash().or_else(|| {
+        current_state
+            .guilds
+            .get(&guild.id)
+            .and_then(|known| known.icon.clone())
+    });
+    current_state.guilds.insert(
+        guild.id.clone(),
+        data::GuildData {
+            id: guild.id.clone(),
+            name: guild.name.clone(),
+            icon,
+        },
+    );
+    refresh_guild_name(current_state);
+}
+
+fn refresh_guild_name(current_state: &mut data::ConnState) {
+    let guild_name = current_state.guild().map(|guild| guild.name.clone());
+    if let Some(channel) = &mut current_state.channel {
+        channel.guild_name = guild_name;
+    }
+}
+
+async fn update_state_from_voice_state(
+    state: Arc<Mutex<data::ConnState>>,
+    voice_state: &protocol::VoiceStateEntry,
+) {
+    let user_id = voice_state.user.id.clone();
+    let mut current_state = state.lock().await;
+
+    let user = data::DiscordUserData {
+        avatar: voice_state.user.avatar.clone(),
+        id: user_id.clone(),
+        username: voice_state.user.username.clone(),
+    };
+    current_state.users.insert(user_id.clone(), user);
+    // Keep whatever SPEAKING_* last told us, and where they sit in the list
+    let (talking, joined, position, last_spoke) = match current_state.voice_states.get(&user_id) {
+        Some(known) => (
+            known.talking,
+            known.joined,
+            known.position,
+            known.last_spoke,
+        ),
+        None => (false, current_state.next_tick(), None, 0),
+    };
+    let flags = &voice_state.voice_state;
     let vs = data::VoiceStateData {
-        mute: voice_state["voice_state"]["mute"].as_bool().unwrap(),
-        deaf: voice_state["voice_state"]["deaf"].as_bool().unwrap(),
-        self_mute: voice_state["voice_state"]["self_mute"].as_bool().unwrap(),
-        self_deaf: voice_state["voice_state"]["self_deaf"].as_bool().unwrap(),
-        suppress: voice_state["voice_state"]["suppress"].as_bool().unwrap(),
-        nick: nick,
-        talking: talking,
+        mute: flags.mute,
+        deaf: flags.deaf,
+        self_mute: flags.self_mute,
+        self_deaf: flags.self_deaf,
+        suppress: flags.suppress,
+        nick: voice_state.display_nick(),
+        talking,
+        local_mute: voice_state.mute,
+        volume: voice_state
+            .volume
+            .map_or(100, |volume| volume.round() as u32),
+        joined,
+        position,
+        last_spoke,
     };
     current_state.voice_states.insert(user_id, vs);
 }
 
 async fn update_state_from_voice_state_list(
     state: Arc<Mutex<data::ConnState>>,
-    voice_state_list: &Value,
+    voice_state_list: &[protocol::VoiceStateEntry],
 ) {
-    for voice_state in voice_state_list.as_array().unwrap() {
+    for (position, voice_state) in voice_state_list.iter().enumerate() {
         update_state_from_voice_state(state.clone(), voice_state).await;
+        if let Some(member) = state
+            .lock()
+            .await
+            .voice_states
+            .get_mut(&voice_state.user.id)
+        {
+            member.position = Some(position);
+        }
     }
 }
 
-pub async fn connector(
-    sender: Arc<Mutex<futures::channel::mpsc::Sender<data::ConnState>>>,
-    recvr: Arc<Mutex<futures::channel::mpsc::Receiver<String>>>,
+// Try each candidate port in turn, returning the first Discord that answers
+async fn connect_any(endpoint: &Endpoint) -> Option<(Socket, String)> {
+    for port in endpoint.ports() {
+        let url = endpoint.url(port);
+        let req = match Request::builder()
+            .uri(url.as_str())
+            .method("GET")
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", generate_key())
+            .header("Host", endpoint.host.as_str())
+            .header("Origin", endpoint.origin.as_str())
+            .body(())
+        {
+            Ok(req) => req,
+            Err(err) => {
+                eprintln!("{}", ConnectorError::InvalidEndpoint(err));
+                return None;
+            }
+        };
+        if let Ok((ws_stream, _response)) = connect_async(req).await {
+            return Some((ws_stream, url));
+        }
+    }
+    None
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type Inbound = Pin<Box<dyn Stream<Item = Result<Message, tungstenite::Error>> + Send>>;
+
+// Our half of the websocket. Notes everything sent when recording, and swallows it
+// when replaying as there is no Discord on the other end
+struct Outbound {
+    sink: Option<SplitSink<Socket, Message>>,
+    recorder: Option<Recorder>,
+}
+
+impl Outbound {
+    async fn send(&mut self, message: Message) -> Result<(), tungstenite::Error> {
+        if let (Some(recorder), Message::Text(raw)) = (&self.recorder, &message) {
+            recorder.record(Direction::Out, raw);
+        }
+        match &mut self.sink {
+            Some(sink) => sink.send(message).await,
+            None => Ok(()),
+        }
+    }
+}
+
+// One session of a recording, each frame released as long after `start` as it
+// originally arrived after the first
+fn replay_stream(frames: Vec<Frame>, start: Instant) -> Inbound {
+    Box::pin(futures::stream::iter(frames).then(move |frame| async move {
+        sleep_until(start + Duration::from_millis(frame.ms)).await;
+        Ok(Message::Text(frame.raw))
+    }))
+}
+
+// What the frontend hasn't been given yet. Every event in order, and the states
+// since the last delivery, dropping the oldest past STATE_BACKLOG
+struct Undelivered {
+    states: VecDeque<data::ConnState>,
+    events: Vec<StateEvent>,
+    // The delivery task is still busy with earlier ones
+    delivering: bool,
+    // Straight to the frontend while nothing is waiting
+    direct: futures::channel::mpsc::Sender<data::ConnState>,
+}
+
+// Sits between the connector and the frontend, so the connector never waits on a
+// slow reader and the newest state always gets through. States go out before the
+// events that led to them
+struct Outbox {
+    undelivered: std::sync::Mutex<Undelivered>,
+    ready: Notify,
+    subscribers: EventSubscribers,
+}
+
+impl Outbox {
+    fn start(
+        mut sender: futures::channel::mpsc::Sender<data::ConnState>,
+        subscribers: EventSubscribers,
+    ) -> Arc<Outbox> {
+        let outbox = Arc::new(Outbox {
+            undelivered: std::sync::Mutex::new(Undelivered {
+                states: VecDeque::new(),
+                events: vec![],
+                delivering: false,
+                direct: sender.clone(),
+            }),
+            ready: Notify::new(),
+            subscribers,
+        });
+        let delivery = outbox.clone();
+        tokio::spawn(async move {
+            loop {
+                delivery.ready.notified().await;
+                loop {
+                    let (states, events) = {
+                        let mut undelivered = delivery.undelivered.lock().unwrap();
+                        if undelivered.states.is_empty() && undelivered.events.is_empty() {
+                            undelivered.delivering = false;
+                            break;
+                        }
+                        undelivered.delivering = true;
+                        (
+                            std::mem::take(&mut undelivered.states),
+                            std::mem::take(&mut undelivered.events),
+                        )
+                    };
+                    for state in states {
+                        // Only fails once the frontend stops listening
+                        let _ = sender.send(state).await;
+                    }
+                    client::broadcast(&delivery.subscribers, &events);
+                }
+            }
+        });
+        outbox
+    }
+
+    fn post(&self, state: Option<data::ConnState>, events: Vec<StateEvent>) {
+        let mut undelivered = self.undelivered.lock().unwrap();
+        let mut state = state;
+        let idle = !undelivered.delivering
+            && undelivered.states.is_empty()
+            && undelivered.events.is_empty();
+        if idle {
+            if let Some(waiting) = state.take() {
+                match undelivered.direct.try_send(waiting) {
+                    Ok(()) => {}
+                    Err(err) if err.is_full() => state = Some(err.into_inner()),
+                    // The frontend stopped listening
+                    Err(_err) => {}
+                }
+            }
+            if state.is_none() {
+                client::broadcast(&self.subscribers, &events);
+                return;
+            }
+        }
+        if let Some(state) = state {
+            undelivered.states.push_back(state);
+            if undelivered.states.len() > STATE_BACKLOG {
+                undelivered.states.pop_front();
+            }
+        }
+        undelivered.events.extend(events);
+        self.ready.notify_one();
+    }
+}
+
+// Hand a changed state to the frontend, along with what changed since the last one
+fn publish(outbox: &Outbox, published: &mut data::ConnState, state: data::ConnState) {
+    if data::calculate_hash(&state) == data::calculate_hash(published) {
+        return;
+    }
+    let events = state.changes_since(published);
+    outbox.post(Some(state.clone()), events);
+    *published = state;
+}
+
+fn set_phase(health: &watch::Sender<ConnectionHealth>, phase: ConnectionPhase) {
+    health.send_if_modified(|health| {
+        if health.phase == phase {
+            return false;
+        }
+        health.phase = phase;
+        if !phase.is_ready() {
+            health.voice = None;
+        }
+        if matches!(
+            phase,
+            ConnectionPhase::Disconnected | ConnectionPhase::Connecting
+        ) {
+            health.endpoint = None;
+        }
+        true
+    });
+}
+
+fn set_endpoint(health: &watch::Sender<ConnectionHealth>, url: &str) {
+    health.send_modify(|health| {
+        health.endpoint = Some(url.to_string());
+    });
+}
+
+fn set_voice_connection(
+    health: &watch::Sender<ConnectionHealth>,
+    status: &protocol::VoiceConnectionStatusData,
 ) {
+    let voice = Some(data::VoiceConnectionData {
+        state: status.state.clone(),
+        hostname: status.hostname.clone(),
+        last_ping: status.last_ping,
+        average_ping: status.average_ping,
+    });
+    health.send_if_modified(|health| {
+        if health.voice == voice {
+            return false;
+        }
+        health.voice = voice;
+        true
+    });
+}
+
+// Move on to Subscribed once every server subscription has been answered. Replies to
+// channel, guild and text channel subscriptions don't count
+fn subscription_answered(
+    health: &watch::Sender<ConnectionHealth>,
+    pending: &mut HashSet<String>,
+    nonce: Option<&str>,
+) {
+    if !nonce.is_some_and(|nonce| pending.remove(nonce)) {
+        return;
+    }
+    if pending.is_empty() && health.borrow().phase == ConnectionPhase::Authenticated {
+        set_phase(health, ConnectionPhase::Subscribed);
+    }
+}
+
+// Which text channel's messages to follow, from DISCERN_TEXT_CHANNEL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextChannel {
+    // The text chat of the voice channel we're in. The default
+    Voice,
+    // Always this channel ID
+    Channel(String),
+    // `off`, no messages at all
+    Off,
+}
+
+impl TextChannel {
+    pub fn from_env() -> TextChannel {
+        match env::var("DISCERN_TEXT_CHANNEL").as_deref() {
+            Err(_) | Ok("") | Ok("voice") => TextChannel::Voice,
+            Ok("off") => TextChannel::Off,
+            Ok(channel_id) => TextChannel::Channel(channel_id.to_string()),
+        }
+    }
+}
+
+// Where to find Discord and how to get a token
+pub struct ConnectorConfig {
+    pub endpoint: Endpoint,
+    pub exchanger: Box<dyn TokenExchanger>,
+    pub tokens: TokenCache,
+    // Play back this recording instead of connecting to Discord
+    pub replay: Option<PathBuf>,
+    // Write every frame sent or received here
+    pub recorder: Option<Recorder>,
+    pub text_channel: TextChannel,
+}
+
+impl ConnectorConfig {
+    pub fn from_env() -> ConnectorConfig {
+        let endpoint = Endpoint::from_env();
+        let exchanger = exchanger_from_env(&endpoint.client_id);
+        let tokens = TokenCache::from_env(&endpoint);
+        ConnectorConfig {
+            endpoint,
+            exchanger,
+            tokens,
+            replay: None,
+            recorder: None,
+            text_channel: TextChannel::from_env(),
+        }
+    }
+
+    // Nothing leaves the machine: the exchange is faked and no token is kept
+    pub fn replay(path: PathBuf) -> ConnectorConfig {
+        ConnectorConfig {
+            endpoint: Endpoint::default(),
+            exchanger: Box::new(ReplayExchanger),
+            tokens: TokenCache::disabled(),
+            replay: Some(path),
+            recorder: None,
+            text_channel: TextChannel::from_env(),
+        }
+    }
+
+    // Honour `--replay FILE` from recording::replay_arg
+    pub fn from_args(matches: &clap::ArgMatches) -> ConnectorConfig {
+        match matches.value_of("replay") {
+            Some(path) => ConnectorConfig::replay(PathBuf::from(path)),
+            None => ConnectorConfig::from_env(),
+        }
+    }
+}
+
+pub async fn connector_with(
+    sender: Arc<Mutex<futures::channel::mpsc::Sender<data::ConnState>>>,
+    config: ConnectorConfig,
+) -> client::DiscordClient {
     let state = Arc::new(Mutex::new(data::ConnState::new()));
     let debug_stdout = true;
+
+    // Frontend commands to websocket output
+    let (command_sender, command_recv) = futures::channel::mpsc::channel::<protocol::Request>(10);
+    let command_recv = Arc::new(Mutex::new(command_recv));
+    let commands = client::CommandClient::new(command_sender);
+    let pending = commands.pending();
+    let (health_sender, health_recv) = watch::channel(ConnectionHealth::new());
+    let subscribers: EventSubscribers = Arc::new(std::sync::Mutex::new(vec![]));
+    let events = subscribers.clone();
+    let outbox = Outbox::start(sender.lock().await.clone(), subscribers);
+    let ConnectorConfig {
+        endpoint,
+        exchanger,
+        tokens,
+        replay,
+        recorder,
+        text_channel,
+    } = config;
+
     tokio::spawn(async move {
+        let mut backoff = Backoff::new();
+        // Last state the frontend was given
+        let mut published = data::ConnState::new();
+        // Stay Disconnected between port scans that find nothing
+        let mut scan_failed = false;
+        // Sessions of the recording still to play, each standing in for one connection
+        let mut sessions = VecDeque::new();
+        let replay_start = Instant::now();
+        if let Some(path) = &replay {
+            match recording::load(path) {
+                Ok(frames) => {
+                    if debug_stdout {
+                        eprintln!("Replaying {}", path.display());
+                    }
+                    sessions = recording::sessions(frames).into();
+                }
+                Err(err) => {
+                    eprintln!("Unable to read {}: {}", path.display(), err);
+                    set_phase(&health_sender, ConnectionPhase::Disconnected);
+                    return;
+                }
+            }
+        }
         loop {
             if debug_stdout {
-                println!("Awaiting connection");
+                eprintln!("Awaiting connection");
             }
-            let req = Request::builder()
-                .uri("ws://127.0.0.1:6463/?v=1&client_id=207646673902501888")
-                .method("GET")
-                .header("Connection", "Upgrade")
-                .header("Upgrade", "websocket")
-                .header("Sec-WebSocket-Version", "13")
-                .header("Sec-WebSocket-Key", generate_key())
-                .header("Host", "127.0.0.1")
-                .header("Origin", "https://streamkit.discord.com")
-                .body(())
-                .unwrap();
-
-            let ws_stream = match connect_async(req).await {
-                Ok(a) => a.0,
-                Err(_err) => {
-                    // Retry in 1 second. Probably make it less often?
-                    sleep(Duration::from_millis(1000)).await;
-                    continue;
+            if !scan_failed {
+                set_phase(&health_sender, ConnectionPhase::Connecting);
+            }
+            let (mut read, sink): (Inbound, _) = match &replay {
+                Some(_) => {
+                    let frames = sessions.pop_front().unwrap_or_default();
+                    (replay_stream(frames, replay_start), None)
                 }
+                None => match connect_any(&endpoint).await {
+                    Some((ws_stream, url)) => {
+                        if debug_stdout {
+                            eprintln!("Connected to local Discord at {}", url);
+                        }
+                        if let Some(recorder) = &recorder {
+                            recorder.session(&url);
+                        }
+                        set_endpoint(&health_sender, &url);
+                        scan_failed = false;
+                        let (write, read) = ws_stream.split();
+                        (Box::pin(read), Some(write))
+                    }
+                    None => {
+                        scan_failed = true;
+                        set_phase(&health_sender, ConnectionPhase::Disconnected);
+                        sleep(backoff.next_delay()).await;
+                        continue;
+                    }
+                },
             };
-            if debug_stdout {
-                println!("Connected to local Discord");
-            }
-            let (write, read) = ws_stream.split();
-            let writer = Arc::new(Mutex::new(write));
+            set_phase(&health_sender, ConnectionPhase::Authorizing);
+            let writer = Arc::new(Mutex::new(Outbound {
+                sink,
+                recorder: recorder.clone(),
+            }));
 
-            // Message thread to writer
-            {
-                let recvr = recvr.clone();
+            // Message thread to writer. Held back until Discord accepts our token
+            let authenticated = Arc::new(Notify::new());
+            let forwarder = {
+                let command_recv = command_recv.clone();
                 let writer = writer.clone();
+                let authenticated = authenticated.clone();
+                let pending = pending.clone();
                 tokio::spawn(async move {
-                    while let Some(event) = recvr.clone().lock().await.next().await {
-                        //println!("{}", event);
-                        send_socket!(writer, [event]);
+                    authenticated.notified().await;
+                    let mut command_recv = command_recv.lock().await;
+                    while let Some(packet) = command_recv.next().await {
+                        if !client::still_pending(&pending, &packet).await {
+                            continue;
+                        }
+                        send_socket!(writer, [packet]);
                     }
-                });
-            }
+                })
+            };
 
-            read.for_each(|message| async {
-                if message.is_err() {
-                    println!("Connection to Discord lost");
-                    state.lock().await.clear();
-                    return;
-                }
-                let copy_state = state.lock().await.clone();
-                let before_hash = data::calculate_hash(&copy_state);
-                let message = message.unwrap();
+            let mut reauthorized = 0;
+            // Nonces of server subscriptions not yet answered
+            let mut subscriptions_pending = HashSet::new();
+            while let Some(message) = read.next().await {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(_err) => {
+                        eprintln!("Connection to Discord lost");
+                        state.lock().await.clear();
+                        break;
+                    }
+                };
                 let writer = writer.clone();
                 match message {
                     tungstenite::Message::Text(raw_data) => {
-                        let data: Value = serde_json::from_str(&raw_data).unwrap();
-                        // Data is a raw JSON object
-                        //println!("{}", raw_data);
-                        match data["cmd"].as_str().unwrap() {
-                            "AUTHORIZE" => {
+                        if let Some(recorder) = &recorder {
+                            recorder.record(Direction::In, &raw_data);
+                        }
+                        let packet = match protocol::Incoming::parse(&raw_data) {
+                            Ok(packet) => packet,
+                            Err(err) => {
+                                client::resolve_unreadable(&pending, &raw_data, &err).await;
+                                if debug_stdout {
+                                    eprintln!("{}", ConnectorError::Protocol(err));
+                                    eprintln!("{}", raw_data);
+                                }
+                                continue;
+                            }
+                        };
+                        client::resolve(&pending, &packet).await;
+                        match packet.payload {
+                            Payload::Response(Response::Authorize(auth)) => {
                                 // Make HTTPS request to auth user
-                                let url = "https://streamkit.discord.com/overlay/token";
-                                let obj = json!({"code": data["data"]["code"]});
-                                let resp: serde_json::Value = reqwest::Client::new()
-                                    .post(url)
-                                    .json(&obj)
-                                    .send()
-                                    .await
-                                    .unwrap()
-                                    .json()
-                                    .await
-                                    .unwrap();
-                                match resp.get("access_token") {
-                                    Some(value) => {
-                                        send_socket!(writer, packet_auth2!(value));
+                                match exchanger.exchange(&auth.code).await {
+                                    Ok(token) => {
+                                        tokens.store(&endpoint.client_id, &token);
+                                        send_socket!(writer, packet_auth2!(token));
                                     }
-                                    None => {
+                                    Err(err) => {
                                         if debug_stdout {
-                                            println!("No access token, failed to connect")
+                                            eprintln!("{}, failed to connect", err)
                                         }
-                                        // TODO Reattempt connect
+                                        // Drop the connection and start over after a backoff
+                                        break;
                                     }
                                 }
                             }
-                            "AUTHENTICATE" => match data["data"].get("access_token") {
-                                None => {
-                                    if debug_stdout {
-                                        println!("Not authorized");
-                                        println!("{:?}", data);
-                                    }
+                            Payload::Response(Response::Authenticate(auth)) => {
+                                send_socket!(writer, packet_req_all_guilds!());
+                                send_socket!(writer, packet_req_selected_voice!());
+                                send_socket!(writer, packet_req_devices!());
+                                let subscriptions = packet_sub_server!();
+                                subscriptions_pending = subscriptions
+                                    .iter()
+                                    .map(|subscription| subscription.nonce.clone())
+                                    .collect();
+                                send_socket!(writer, subscriptions);
+                                update_state_from_self(state.clone(), &auth.user).await;
+                                authenticated.notify_one();
+                                backoff.reset();
+                                set_phase(&health_sender, ConnectionPhase::Authenticated);
+                            }
+                            Payload::Response(Response::Subscribe) => {
+                                subscription_answered(
+                                    &health_sender,
+                                    &mut subscriptions_pending,
+                                    packet.nonce.as_deref(),
+                                );
+                            }
+                            Payload::Response(Response::GetGuilds(list)) => {
+                                let mut current_state = state.lock().await;
+                                current_state.guilds.clear();
+                                for guild in list.guilds.iter() {
+                                    update_state_from_guild(&mut current_state, guild);
                                 }
-                                Some(_value) => {
-                                    send_socket!(writer, packet_req_all_guilds!());
-                                    send_socket!(writer, packet_req_selected_voice!());
-                                    send_socket!(writer, packet_sub_server!());
-                                    match data["data"]["user"]["id"].as_str() {
-                                        Some(value) => {
-                                            state.lock().await.user_id = Some(value.to_string());
+                            }
+                            Payload::Response(Response::GetVoiceSettings(settings))
+                            | Payload::Response(Response::SetVoiceSettings(settings))
+                            | Payload::Event(Event::VoiceSettingsUpdate(settings)) => {
+                                update_state_from_voice_settings(state.clone(), &settings).await;
+                            }
+                            Payload::Response(Response::SetUserVoiceSettings(settings)) => {
+                                update_state_from_user_voice_settings(state.clone(), &settings)
+                                    .await;
+                            }
+                            Payload::Response(Response::GetSelectedVoiceChannel(channel)) => {
+                                match channel {
+                                    Some(channel) => {
+                                        {
+                                            let mut current_state = state.lock().await;
+                                            current_state.voice_channel = Some(channel.id.clone());
+                                            update_state_from_channel(&mut current_state, &channel);
                                         }
-                                        None => {
-                                            state.lock().await.user_id = None;
+                                        update_state_from_voice_state_list(
+                                            state.clone(),
+                                            &channel.voice_states,
+                                        )
+                                        .await;
+                                        send_socket!(
+                                            writer,
+                                            packet_sub_voice_channel!(channel.id.as_str())
+                                        );
+                                        if let Some(guild_id) = &channel.guild_id {
+                                            send_socket!(writer, packet_sub_guild!(guild_id));
                                         }
                                     }
+                                    None => {
+                                        user_left_channel(state.clone()).await;
+                                    }
+                                }
+                            }
+                            Payload::Event(Event::Ready) => {
+                                set_phase(&health_sender, ConnectionPhase::Authorizing);
+                                // Skip AUTHORIZE and the token exchange if we still have a token
+                                match tokens.load(&endpoint.client_id) {
+                                    Some(token) => {
+                                        send_socket!(writer, packet_auth2!(token));
+                                    }
+                                    None => {
+                                        send_socket!(writer, packet_auth!(endpoint.client_id));
+                                    }
                                 }
-                            },
-                            "GET_GUILDS" => {}
-                            "GET_SELECTED_VOICE_CHANNEL" => match data["data"].get("id") {
-                                Some(value) => {
-                                    state.lock().await.voice_channel =
-                                        Some(value.as_str().unwrap().parse().unwrap());
-                                    update_state_from_voice_state_list(
-                                        state.clone(),
-                                        &data["data"]["voice_states"],
+                            }
+                            Payload::Event(Event::SpeakingStart(speaking)) => {
+                                if state.lock().await.voice_channel.is_none() {
+                                    send_socket!(writer, packet_req_selected_voice!());
+                                }
+                                let result =
+                                    set_user_talking(state.clone(), speaking.user_id, true).await;
+                                skip_on_error(debug_stdout, result);
+                            }
+                            Payload::Event(Event::SpeakingStop(speaking)) => {
+                                let result =
+                                    set_user_talking(state.clone(), speaking.user_id, false).await;
+                                skip_on_error(debug_stdout, result);
+                            }
+                            Payload::Event(Event::VoiceStateDelete(voice_state)) => {
+                                let (authenticated, is_self) = {
+                                    let current_state = state.lock().await;
+                                    (
+                                        current_state.self_user.is_some(),
+                                        current_state.is_self(&voice_state.user.id),
                                     )
-                                    .await;
-                                    send_socket!(
-                                        writer,
-                                        packet_sub_voice_channel!(value.as_str().unwrap())
+                                };
+                                if !authenticated {
+                                    skip_on_error(
+                                        debug_stdout,
+                                        Err(ConnectorError::NotAuthenticated),
                                     );
-                                }
-                                None => {
+                                } else if is_self {
                                     user_left_channel(state.clone()).await;
+                                } else {
+                                    user_left(state.clone(), &voice_state.user.id).await;
                                 }
-                            },
-                            "DISPATCH" => {
-                                match data["evt"].as_str().unwrap() {
-                                    "READY" => {
-                                        send_socket!(writer, packet_auth!("207646673902501888"));
-                                    }
-                                    "SPEAKING_START" => {
-                                        let id = data["data"]["user_id"]
-                                            .as_str()
-                                            .unwrap()
-                                            .to_string()
-                                            .clone();
-                                        if state.lock().await.voice_channel.is_none() {
-                                            send_socket!(writer, packet_req_selected_voice!());
-                                        }
-                                        set_user_talking(state.clone(), id, true).await;
-                                    }
-                                    "SPEAKING_STOP" => {
-                                        let id = data["data"]["user_id"]
-                                            .as_str()
-                                            .unwrap()
-                                            .to_string()
-                                            .clone();
-                                        set_user_talking(state.clone(), id, false).await;
-                                    }
-                                    "VOICE_STATE_DELETE" => {
-                                        //println!("{:?}", data);
-                                        let id = data["data"]["user"]["id"].clone();
-                                        let user_id =
-                                            state.lock().await.user_id.as_ref().unwrap().clone();
-                                        if id == user_id {
-                                            user_left_channel(state.clone()).await;
-                                        }
-                                    }
-                                    "VOICE_STATE_CREATE" => {
-                                        if state.lock().await.voice_channel.is_none() {
-                                            send_socket!(writer, packet_req_selected_voice!());
-                                        }
-                                    }
-                                    "VOICE_STATE_UPDATE" => {
-                                        let _id = data["data"]["user"]["id"].clone();
-                                        update_state_from_voice_state(state.clone(), &data["data"])
-                                            .await;
+                            }
+                            Payload::Event(Event::VoiceStateCreate(voice_state)) => {
+                                // Without a channel we can't tell which one they joined
+                                if state.lock().await.voice_channel.is_none() {
+                                    send_socket!(writer, packet_req_selected_voice!());
+                                } else {
+                                    update_state_from_voice_state(state.clone(), &voice_state)
+                                        .await;
+                                }
+                            }
+                            Payload::Event(Event::VoiceStateUpdate(voice_state)) => {
+                                update_state_from_voice_state(state.clone(), &voice_state).await;
+                            }
+                            Payload::Event(Event::VoiceChannelSelect(_)) => {
+                                // User has manually chosen to join a room
+                                send_socket!(writer, packet_req_selected_voice!());
+                                // Let's ask for more info
+                            }
+                            Payload::Event(Event::ChannelUpdate(channel)) => {
+                                let mut current_state = state.lock().await;
+                                if current_state.voice_channel.as_ref() == Some(&channel.id) {
+                                    update_state_from_channel(&mut current_state, &channel);
+                                }
+                            }
+                            Payload::Event(Event::GuildStatus(status)) => {
+                                update_state_from_guild(&mut *state.lock().await, &status.guild);
+                            }
+                            Payload::Event(Event::GuildCreate(guild)) => {
+                                update_state_from_guild(&mut *state.lock().await, &guild);
+                            }
+                            Payload::Event(
+                                event @ (Event::MessageCreate(_)
+                                | Event::MessageUpdate(_)
+                                | Event::MessageDelete(_)),
+                            ) => {
+                                update_state_from_message(state.clone(), &event).await;
+                            }
+                            Payload::Event(Event::VoiceConnectionStatus(status)) => {
+                                // Goes to the health channel, not ConnState, so pings
+                                // don't redraw every overlay
+                                set_voice_connection(&health_sender, &status);
+                            }
+                            Payload::Error { cmd, error } => {
+                                if debug_stdout {
+                                    if cmd == "AUTHENTICATE" {
+                                        eprintln!("Not authorized");
                                     }
-                                    "VOICE_CHANNEL_SELECT" => {
-                                        // User has manually chosen to join a room
-                                        send_socket!(writer, packet_req_selected_voice!());
-                                        // Let's ask for more info
+                                    eprintln!("{} failed: {} {}", cmd, error.code, error.message);
+                                }
+                                match cmd.as_str() {
+                                    "AUTHORIZE" => {
+                                        // Turned down in Discord, or this client isn't allowed.
+                                        // Nothing more will happen here, so start over later
+                                        eprintln!(
+                                            "Discord refused to authorize: {}",
+                                            error.message
+                                        );
+                                        break;
                                     }
-                                    "VOICE_CONNECTION_STATUS" => {
-                                        if debug_stdout {
-                                            // TODO Potentially make this part of the conn state
-                                            // But be aware that allowing this to change the state will
-                                            // Cause the overlay to render every couple of seconds for no
-                                            // effect
-                                            println!("{}: {}", data["evt"], data["data"]["state"]);
+                                    "AUTHENTICATE" => {
+                                        // Token was refused, ask for a fresh one
+                                        tokens.forget(&endpoint.client_id);
+                                        reauthorized += 1;
+                                        if reauthorized > MAX_REAUTHORIZE {
+                                            break;
                                         }
+                                        set_phase(&health_sender, ConnectionPhase::Authorizing);
+                                        send_socket!(writer, packet_auth!(endpoint.client_id));
                                     }
-                                    _ => {
-                                        if debug_stdout {
-                                            println!("{:?}", data);
-                                        }
+                                    "SUBSCRIBE" => {
+                                        subscription_answered(
+                                            &health_sender,
+                                            &mut subscriptions_pending,
+                                            packet.nonce.as_deref(),
+                                        );
                                     }
+                                    _ => {}
                                 }
                             }
-                            _ => {
+                            other => {
                                 if debug_stdout {
-                                    println!("{:?}", data);
+                                    eprintln!("{:?}", other);
                                 }
                             }
                         }
-                        let copy_state = state.lock().await.clone();
-                        if before_hash != data::calculate_hash(&copy_state) {
-                            match sender.lock().await.try_send(copy_state) {
-                                Ok(_) => {}
-                                Err(_e) => {}
+                        let follow = follow_text_channel(&mut *state.lock().await, &text_channel);
+                        if let Some((previous, wanted)) = follow {
+                            if let Some(channel_id) = previous {
+                                send_socket!(
+                                    writer,
+                                    packet_unsub_text_channel!(channel_id.as_str())
+                                );
+                            }
+                            if let Some(channel_id) = wanted {
+                                send_socket!(writer, packet_sub_text_channel!(channel_id.as_str()));
                             }
                         }
+                        let current = state.lock().await.clone();
+                        publish(&outbox, &mut published, current);
                     }
                     tungstenite::Message::Binary(_raw_data) => {}
                     tungstenite::Message::Ping(_raw_data) => {}
@@ -306,8 +1052,31 @@ pub async fn connector(
                         state.lock().await.clear();
                     }
                 }
-            })
-            .await;
+            }
+            forwarder.abort();
+            // A finished replay keeps its final state up
+            let replay_over = replay.is_some() && sessions.is_empty();
+            if !replay_over {
+                state.lock().await.clear();
+            }
+            let current = state.lock().await.clone();
+            publish(&outbox, &mut published, current);
+            outbox.post(None, vec![StateEvent::Disconnected]);
+            set_phase(&health_sender, ConnectionPhase::Disconnected);
+            // Nobody is going to answer these now
+            pending.lock().await.clear();
+            if replay_over {
+                // Leave the final state of the recording up
+                if debug_stdout {
+                    eprintln!("Replay finished");
+                }
+                return;
+            }
+            // The next session of a replay already waits for its own frames
+            if replay.is_none() {
+                sleep(backoff.next_delay()).await;
+            }
         }
     });
+    client::DiscordClient::new(commands, health_recv, events)
 }
@@ -1,7 +1,8 @@
 extern crate clap;
 extern crate serde_json;
-use crate::data::ConnState;
+use crate::data::{ConnState, ConnectionHealth, MemberOrder, OverlayMode};
 use cairorender::DiscordAvatarRaw;
+use clap::command;
 use cosmic::iced::wayland::actions::layer_surface::SctkLayerSurfaceSettings;
 use cosmic::iced::wayland::actions::window::SctkWindowSettings;
 use cosmic::iced::widget::{column, container, image, row, text};
@@ -18,11 +19,21 @@ use iced_sctk::settings::InitialSurface;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 
 mod cairorender;
+mod client;
 mod core;
 mod data;
+mod endpoint;
 mod macros;
+mod protocol;
+mod recording;
+mod token;
+
+// Shown for users without an avatar of their own
+const DEFAULT_AVATAR: &[u8] = include_bytes!("../assets/default-avatar.png");
 
 pub enum Location {
     Left,
@@ -31,20 +42,31 @@ pub enum Location {
 
 pub struct Preferences {
     location: Location,
+    mode: OverlayMode,
+    order: MemberOrder,
+    // How long messages stay up, None to keep them
+    fade: Option<Duration>,
 }
 
 pub struct App {
     height: f32,
     preferences: Preferences,
     state: ConnState,
+    // Connection or voice warning to show, if any
+    warning: Option<String>,
+    // When shown messages next need a redraw to fade, in data::now_ms() time
+    fade_due: Option<u64>,
     recv_state: RefCell<Option<mpsc::Receiver<ConnState>>>,
+    recv_health: RefCell<Option<watch::Receiver<ConnectionHealth>>>,
     recv_avatar: RefCell<Option<mpsc::Receiver<DiscordAvatarRaw>>>,
     send_avatar: Arc<std::sync::Mutex<mpsc::Sender<ConnState>>>,
     avatar_handler: Arc<std::sync::Mutex<HashMap<String, image::Handle>>>,
+    default_avatar: image::Handle,
 }
 
 pub struct UiFlags {
     recv_state: mpsc::Receiver<ConnState>,
+    recv_health: watch::Receiver<ConnectionHealth>,
     recv_avatar: mpsc::Receiver<DiscordAvatarRaw>,
     send_avatar: mpsc::Sender<ConnState>,
 }
@@ -54,6 +76,9 @@ pub struct UiFlags {
 pub enum Message {
     StateRecv(ConnState),
     AvatarRecv(DiscordAvatarRaw),
+    HealthRecv(Option<String>),
+    // Time to redraw fading messages
+    Tick,
 }
 
 struct NormalStyle;
@@ -64,6 +89,9 @@ struct NormalImageStyle;
 struct TalkingImageStyle;
 struct MuteImageStyle;
 
+// Text channel message, fading out with its opacity
+struct MessageStyle(f32);
+
 impl iced::widget::container::StyleSheet for NormalStyle {
     type Style = iced::Theme;
 
@@ -88,6 +116,35 @@ impl iced::widget::container::StyleSheet for NormalStyle {
         }
     }
 }
+impl iced::widget::container::StyleSheet for MessageStyle {
+    type Style = iced::Theme;
+
+    fn appearance(&self, _style: &Self::Style) -> container::Appearance {
+        let mut border = iced::Border::with_radius(5.0);
+        border.color = iced::Color {
+            r: 0.0,
+            g: 0.0,
+            b: 0.0,
+            a: 0.0,
+        };
+        container::Appearance {
+            background: Some(iced::Background::Color(iced::Color {
+                r: 0.0,
+                g: 0.0,
+                b: 0.0,
+                a: 0.5 * self.0,
+            })),
+            text_color: Some(iced::Color {
+                r: 1.0,
+                g: 1.0,
+                b: 1.0,
+                a: self.0,
+            }),
+            border: border,
+            ..Default::default()
+        }
+    }
+}
 impl iced::widget::container::StyleSheet for TalkingStyle {
     type Style = iced::Theme;
 
@@ -232,7 +289,6 @@ impl Application for App {
                         }
                     }
                 }
-                return iced::Command::none();
             }
             Message::AvatarRecv(msg) => {
                 match msg.raw {
@@ -246,8 +302,33 @@ impl Application for App {
                     None => {}
                 };
             }
+            Message::HealthRecv(warning) => {
+                self.warning = warning;
+            }
+            Message::Tick => {}
+        }
+        let now = data::now_ms();
+        let visible = self
+            .state
+            .visible_messages(now, self.preferences.fade)
+            .len();
+        // Tick quickly while a message fades, otherwise wake once when the next one starts to
+        self.fade_due = self
+            .state
+            .fade_due(now, self.preferences.fade)
+            .map(|due| due.max(now + macros::FADE_REDRAW.as_millis() as u64));
+        let shown = self
+            .state
+            .shown_members(self.preferences.mode, self.preferences.order)
+            .len();
+        let mut height = (shown as f32) * 64.0;
+        if self.warning.is_some() {
+            height += 32.0;
         }
-        let height = (self.state.users.len() as f32) * 64.0;
+        if self.state.channel.is_some() && self.preferences.mode.shows_header() {
+            height += 32.0;
+        }
+        height += (visible as f32) * 32.0;
         if self.height != height {
             println!("Resizing {} >  {}", self.height, height);
             self.height = height;
@@ -269,82 +350,178 @@ impl Application for App {
         println!("Rerender");
         let mut window_container = column([]);
 
-        for (id, value) in self.state.users.iter() {
-            let value = value.clone();
-            if let Some(voice_data) = self.state.voice_states.get(id) {
-                let avatar_key = format!("{}/{}", id, value.avatar.unwrap());
-                let image_handle = match self.avatar_handler.lock().unwrap().get(&avatar_key) {
-                    Some(handle) => handle.clone(),
-                    None => {
-                        image::Handle::from_path("/home/triggerhapp/.local/share/icons/hicolor/256x256/apps/discover-overlay-tray.png")
-                    }
-                };
+        if let Some(warning) = &self.warning {
+            let warning = container(
+                container(text(warning.clone()))
+                    .padding(4)
+                    .width(Length::Shrink)
+                    .height(Length::Shrink)
+                    .style(iced::theme::Container::Custom(Box::new(MuteStyle))),
+            )
+            .width(Length::Fill)
+            .height(Length::Fixed(32.0))
+            .center_y()
+            .align_x(match self.preferences.location {
+                Location::Left => iced::alignment::Horizontal::Left,
+                Location::Right => iced::alignment::Horizontal::Right,
+            });
+            window_container = window_container.push(warning);
+        }
 
-                let inner_image = Element::from(
-                    image::Image::<image::Handle>::new(image_handle)
-                        .border_radius([32.0, 32.0, 32.0, 32.0])
-                        .width(Length::Fixed(64.0))
-                        .height(Length::Fixed(64.0)),
-                );
-                let image = container(inner_image).style(if voice_data.talking {
-                    iced::theme::Container::Custom(Box::new(TalkingImageStyle))
-                } else {
-                    if voice_data.mute
-                        || voice_data.deaf
-                        || voice_data.self_deaf
-                        || voice_data.self_mute
-                    {
-                        iced::theme::Container::Custom(Box::new(MuteImageStyle))
-                    } else {
-                        iced::theme::Container::Custom(Box::new(NormalImageStyle))
-                    }
-                });
-                let text = container(
-                    container(text(
-                        voice_data.nick.clone().unwrap_or(value.username.clone()),
-                    ))
+        if let Some(channel) = self
+            .state
+            .channel
+            .as_ref()
+            .filter(|_| self.preferences.mode.shows_header())
+        {
+            let title = Element::from(
+                container(text(channel.title()))
                     .padding(4)
                     .width(Length::Shrink)
                     .height(Length::Shrink)
-                    .style(if voice_data.talking {
-                        iced::theme::Container::Custom(Box::new(TalkingStyle))
-                    } else {
-                        if voice_data.mute
-                            || voice_data.deaf
-                            || voice_data.self_mute
-                            || voice_data.self_deaf
-                        {
-                            iced::theme::Container::Custom(Box::new(MuteStyle))
-                        } else {
-                            iced::theme::Container::Custom(Box::new(NormalStyle))
-                        }
-                    }),
-                )
+                    .style(iced::theme::Container::Custom(Box::new(NormalStyle))),
+            );
+            let icon = self
+                .state
+                .guild()
+                .and_then(|guild| guild.icon_key())
+                .and_then(|icon_key| self.avatar_handler.lock().unwrap().get(&icon_key).cloned())
+                .map(|handle| {
+                    Element::from(
+                        image::Image::<image::Handle>::new(handle)
+                            .border_radius([8.0, 8.0, 8.0, 8.0])
+                            .width(Length::Fixed(32.0))
+                            .height(Length::Fixed(32.0)),
+                    )
+                });
+            let header_row = match (icon, &self.preferences.location) {
+                (Some(icon), Location::Left) => row([icon, title]),
+                (Some(icon), Location::Right) => row([title, icon]),
+                (None, _) => row([title]),
+            };
+            let header = container(header_row.align_items(iced::Alignment::Center))
                 .width(Length::Fill)
-                .height(Length::Fixed(64.0))
+                .height(Length::Fixed(32.0))
                 .center_y()
                 .align_x(match self.preferences.location {
                     Location::Left => iced::alignment::Horizontal::Left,
                     Location::Right => iced::alignment::Horizontal::Right,
                 });
+            window_container = window_container.push(header);
+        }
 
-                let row = match self.preferences.location {
-                    Location::Left => row([Element::from(image), Element::from(text)]),
-                    Location::Right => row([Element::from(text), Element::from(image)]),
-                };
-                let row_cont = container(row)
-                    .height(Length::Shrink)
+        for member in self
+            .state
+            .shown_members(self.preferences.mode, self.preferences.order)
+        {
+            let id = member.id;
+            let value = member.user.clone();
+            let voice_data = member.voice_state;
+            // Users without an avatar, and avatars still downloading, get the default one
+            let image_handle = value
+                .avatar
+                .as_ref()
+                .and_then(|avatar| {
+                    let avatar_key = format!("{}/{}", id, avatar);
+                    self.avatar_handler
+                        .lock()
+                        .unwrap()
+                        .get(&avatar_key)
+                        .cloned()
+                })
+                .unwrap_or_else(|| self.default_avatar.clone());
+
+            let inner_image = Element::from(
+                image::Image::<image::Handle>::new(image_handle)
+                    .border_radius([32.0, 32.0, 32.0, 32.0])
+                    .width(Length::Fixed(64.0))
+                    .height(Length::Fixed(64.0)),
+            );
+            let image = container(inner_image).style(if voice_data.talking {
+                iced::theme::Container::Custom(Box::new(TalkingImageStyle))
+            } else {
+                if voice_data.mute
+                    || voice_data.deaf
+                    || voice_data.self_deaf
+                    || voice_data.self_mute
+                {
+                    iced::theme::Container::Custom(Box::new(MuteImageStyle))
+                } else {
+                    iced::theme::Container::Custom(Box::new(NormalImageStyle))
+                }
+            });
+            let text = container(
+                container(text(
+                    voice_data.nick.clone().unwrap_or(value.username.clone()),
+                ))
+                .padding(4)
+                .width(Length::Shrink)
+                .height(Length::Shrink)
+                .style(if voice_data.talking {
+                    iced::theme::Container::Custom(Box::new(TalkingStyle))
+                } else {
+                    if voice_data.mute
+                        || voice_data.deaf
+                        || voice_data.self_mute
+                        || voice_data.self_deaf
+                    {
+                        iced::theme::Container::Custom(Box::new(MuteStyle))
+                    } else {
+                        iced::theme::Container::Custom(Box::new(NormalStyle))
+                    }
+                }),
+            )
+            .width(Length::Fill)
+            .height(Length::Fixed(64.0))
+            .center_y()
+            .align_x(match self.preferences.location {
+                Location::Left => iced::alignment::Horizontal::Left,
+                Location::Right => iced::alignment::Horizontal::Right,
+            });
+
+            let row = match self.preferences.location {
+                Location::Left => row([Element::from(image), Element::from(text)]),
+                Location::Right => row([Element::from(text), Element::from(image)]),
+            };
+            let row_cont = container(row)
+                .height(Length::Shrink)
+                .width(Length::Shrink)
+                .height(Length::Shrink);
+            window_container = window_container.push(row_cont);
+        }
+
+        let now = data::now_ms();
+        for message in self.state.visible_messages(now, self.preferences.fade) {
+            let opacity = message.opacity(now, self.preferences.fade) as f32;
+            let summary: String = message
+                .summary()
+                .chars()
+                .take(macros::MESSAGE_CHARS)
+                .collect();
+            let line = container(
+                container(text(summary))
+                    .padding(4)
                     .width(Length::Shrink)
-                    .height(Length::Shrink);
-                window_container = window_container.push(row_cont);
-            }
+                    .height(Length::Shrink)
+                    .style(iced::theme::Container::Custom(Box::new(MessageStyle(
+                        opacity,
+                    )))),
+            )
+            .width(Length::Fill)
+            .height(Length::Fixed(32.0))
+            .center_y()
+            .align_x(match self.preferences.location {
+                Location::Left => iced::alignment::Horizontal::Left,
+                Location::Right => iced::alignment::Horizontal::Right,
+            });
+            window_container = window_container.push(line);
         }
 
         Element::from(window_container)
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        Subscription::batch([
+        let mut subscriptions = vec![
             iced::subscription::unfold(
                 "connstate changes",
                 self.recv_state.take(),
@@ -353,6 +530,26 @@ impl Application for App {
                     (Message::StateRecv(new_state), receiver)
                 },
             ),
+            iced::subscription::unfold(
+                "connection health changes",
+                (self.recv_health.take(), self.warning.clone()),
+                move |(mut receiver, warning)| async move {
+                    // Pings change often, only wake the UI when the warning should change
+                    loop {
+                        let health = receiver.as_mut().unwrap();
+                        if health.changed().await.is_err() {
+                            std::future::pending::<()>().await;
+                        }
+                        let now_warning = health.borrow().warning();
+                        if now_warning != warning {
+                            return (
+                                Message::HealthRecv(now_warning.clone()),
+                                (receiver, now_warning),
+                            );
+                        }
+                    }
+                },
+            ),
             iced::subscription::unfold(
                 "avatar changes",
                 self.recv_avatar.take(),
@@ -361,21 +558,45 @@ impl Application for App {
                     (Message::AvatarRecv(new_avatar_data), receiver)
                 },
             ),
-        ])
+        ];
+        if let Some(due) = self.fade_due {
+            // Keyed by the deadline, so each update that moves it replaces the wakeup
+            subscriptions.push(iced::subscription::unfold(
+                ("message fade", due),
+                false,
+                move |fired| async move {
+                    if fired {
+                        std::future::pending::<()>().await;
+                    }
+                    let wait = due.saturating_sub(data::now_ms());
+                    tokio::time::sleep(Duration::from_millis(wait)).await;
+                    (Message::Tick, true)
+                },
+            ));
+        }
+        Subscription::batch(subscriptions)
     }
 
     fn new(input: Self::Flags) -> (Self, iced::Command<Self::Message>) {
+        let warning = input.recv_health.borrow().warning();
         (
             App {
                 height: 0f32,
                 preferences: Preferences {
                     location: Location::Right,
+                    mode: OverlayMode::from_env(),
+                    order: MemberOrder::from_env(),
+                    fade: data::message_fade_from_env(),
                 },
                 state: ConnState::new(),
+                warning,
+                fade_due: None,
                 recv_state: RefCell::new(Some(input.recv_state)),
+                recv_health: RefCell::new(Some(input.recv_health)),
                 recv_avatar: RefCell::new(Some(input.recv_avatar)),
                 send_avatar: Arc::new(std::sync::Mutex::new(input.send_avatar)),
                 avatar_handler: Arc::new(std::sync::Mutex::new(HashMap::new())),
+                default_avatar: image::Handle::from_memory(DEFAULT_AVATAR),
             },
             iced::Command::none(),
         )
@@ -392,6 +613,8 @@ impl Application for App {
 
 #[tokio::main]
 async fn main() {
+    let matches = command!().arg(recording::replay_arg()).get_matches();
+
     // Avatar to main thread
     let (avatar_request_sender, avatar_request_recv) =
         futures::channel::mpsc::channel::<ConnState>(10);
@@ -406,18 +629,17 @@ async fn main() {
     let (event_sender, event_recv) = futures::channel::mpsc::channel::<ConnState>(10);
     let event_sender = Arc::new(Mutex::new(event_sender));
 
-    // Main thread messages to Websocket output
-    let (msg_sender, msg_recv) = futures::channel::mpsc::channel::<String>(10);
-    let _msg_sender = Arc::new(Mutex::new(msg_sender));
-    let msg_recv = Arc::new(Mutex::new(msg_recv));
-
     // Start a thread for connection
     let connector_event_sender = event_sender.clone();
-    let connector_msg_recv = msg_recv.clone();
-    core::connector(connector_event_sender.clone(), connector_msg_recv.clone()).await;
+    let discord = core::connector_with(
+        connector_event_sender.clone(),
+        core::ConnectorConfig::from_args(&matches),
+    )
+    .await;
 
     let input = UiFlags {
         recv_state: event_recv,
+        recv_health: discord.health(),
         recv_avatar: avatar_done_recv,
         send_avatar: avatar_request_sender,
     };
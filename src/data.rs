@@ -1,15 +1,23 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Reverse;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::hash_map::HashMap;
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, Hash)]
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
 pub struct DiscordUserData {
     pub avatar: Option<String>,
     pub id: String,
     pub username: String,
 }
 
-#[derive(Debug, Clone, Hash)]
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
 pub struct VoiceStateData {
     pub mute: bool,
     pub self_mute: bool,
@@ -18,14 +26,466 @@ pub struct VoiceStateData {
     pub suppress: bool,
     pub nick: Option<String>,
     pub talking: bool,
+    // Muted by us, for us only
+    pub local_mute: bool,
+    // Local volume in percent, 0 - 200
+    pub volume: u32,
+    // ConnState::tick when we first saw them in the channel
+    pub joined: u64,
+    // Index in the member list Discord last sent us. None for anyone who joined since
+    pub position: Option<usize>,
+    // ConnState::tick when they last started speaking, 0 if they haven't
+    pub last_spoke: u64,
 }
 
-#[derive(Debug, Clone)]
+// Our own audio setup, as last reported by Discord
+#[derive(Debug, Clone, Default, PartialEq, Hash, Serialize, Deserialize)]
+pub struct VoiceSettingsData {
+    pub input_device: String,
+    pub output_device: String,
+    // Percent, 0 - 100
+    pub input_volume: u32,
+    // Percent, 0 - 200
+    pub output_volume: u32,
+    // `VOICE_ACTIVITY` or `PUSH_TO_TALK`
+    pub mode: String,
+    pub noise_suppression: bool,
+    pub echo_cancellation: bool,
+    pub automatic_gain_control: bool,
+    pub mute: bool,
+    pub deaf: bool,
+}
+
+// Us. Known from AUTHENTICATE on, whether or not we're in a channel
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SelfUserData {
+    pub user: DiscordUserData,
+    // Our own mute and deafen, as set in Discord
+    pub mute: bool,
+    pub deaf: bool,
+    // Only ever true while we're in a voice channel
+    pub speaking: bool,
+}
+
+// The voice channel we're in, as Discord describes it
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+pub struct VoiceChannelData {
+    pub id: String,
+    pub name: String,
+    // None for DMs and group calls
+    pub guild_id: Option<String>,
+    // None outside of guilds, or until Discord has told us
+    pub guild_name: Option<String>,
+    // Discord channel type, 2 for voice and 13 for stage channels
+    #[serde(rename = "type")]
+    pub kind: Option<u32>,
+    // Bits per second
+    pub bitrate: Option<u32>,
+    // 0 or None for no limit
+    pub user_limit: Option<u32>,
+}
+
+impl VoiceChannelData {
+    // "#General — My Guild", or just "#General" outside of guilds
+    #[allow(dead_code)]
+    pub fn title(&self) -> String {
+        match &self.guild_name {
+            Some(guild_name) => format!("#{} \u{2014} {}", self.name, guild_name),
+            None => format!("#{}", self.name),
+        }
+    }
+}
+
+// A guild we're in, from GET_GUILDS, GUILD_CREATE and GUILD_STATUS
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+pub struct GuildData {
+    pub id: String,
+    pub name: String,
+    // Hash of the guild icon, None if it has none
+    pub icon: Option<String>,
+}
+
+impl GuildData {
+    // Where the icon lives on the Discord CDN, minus the extension. Also the key it's
+    // stored under once avatar_downloader has fetched it
+    #[allow(dead_code)]
+    pub fn icon_key(&self) -> Option<String> {
+        self.icon
+            .as_ref()
+            .map(|icon| format!("icons/{}/{}", self.id, icon))
+    }
+}
+
+// Most messages kept for the text channel, oldest are dropped first
+pub const MESSAGE_LIMIT: usize = 50;
+
+// Link preview or other rich content of a message
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+pub struct EmbedData {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+}
+
+// File attached to a message. Only what's needed to mention it, never the file itself
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+pub struct AttachmentData {
+    pub filename: String,
+    // Bytes
+    pub size: u64,
+    pub url: Option<String>,
+}
+
+// One message in the followed text channel
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+pub struct TextMessageData {
+    pub id: String,
+    pub author_id: Option<String>,
+    // Nickname, or username without one
+    pub author: String,
+    pub content: String,
+    pub embeds: Vec<EmbedData>,
+    pub attachments: Vec<AttachmentData>,
+    // Milliseconds since the Unix epoch when we first saw it. Edits don't reset it
+    pub received: u64,
+}
+
+impl TextMessageData {
+    // One line for overlays: content, then any attachments and embed titles in brackets
+    #[allow(dead_code)]
+    pub fn summary(&self) -> String {
+        let mut parts = vec![];
+        if !self.content.is_empty() {
+            parts.push(self.content.replace('\n', " "));
+        }
+        for attachment in self.attachments.iter() {
+            parts.push(format!("[{}]", attachment.filename));
+        }
+        for embed in self.embeds.iter() {
+            if let Some(title) = &embed.title {
+                parts.push(format!("[{}]", title));
+            }
+        }
+        format!("{}: {}", self.author, parts.join(" "))
+    }
+
+    // 1.0 while fresh, falling to 0.0 over the last quarter of `fade`. Always 1.0 without one
+    #[allow(dead_code)]
+    pub fn opacity(&self, now: u64, fade: Option<Duration>) -> f64 {
+        let fade = match fade {
+            Some(fade) => fade.as_millis() as f64,
+            None => return 1.0,
+        };
+        let left = fade - now.saturating_sub(self.received) as f64;
+        (left / (fade / 4.0)).clamp(0.0, 1.0)
+    }
+}
+
+// Now, in the unit of TextMessageData::received
+#[allow(dead_code)]
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+// How long messages stay on overlays, from DISCERN_MESSAGE_FADE in seconds.
+// Defaults to 30 seconds, 0 keeps them up until they drop out of the buffer
+#[allow(dead_code)]
+pub fn message_fade_from_env() -> Option<Duration> {
+    let seconds = match env::var("DISCERN_MESSAGE_FADE") {
+        Ok(seconds) => seconds.parse::<u64>().unwrap_or_else(|_| {
+            eprintln!(
+                "DISCERN_MESSAGE_FADE must be a whole number of seconds, not `{}`",
+                seconds
+            );
+            30
+        }),
+        Err(_) => 30,
+    };
+    match seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
+}
+
+impl VoiceStateData {
+    pub fn is_muted(&self) -> bool {
+        self.mute || self.self_mute
+    }
+
+    pub fn is_deafened(&self) -> bool {
+        self.deaf || self.self_deaf
+    }
+}
+
+// How to order the members of the channel on screen. Picked with DISCERN_MEMBER_ORDER
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemberOrder {
+    // Whoever we saw first stays on top
+    #[default]
+    Joined,
+    // By name shown, ignoring case
+    Alphabetical,
+    // As Discord lists the channel, newcomers last
+    Discord,
+    // Us, then everyone else in join order
+    SelfFirst,
+    // Most recent to start speaking on top
+    RecentSpeaker,
+}
+
+impl FromStr for MemberOrder {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<MemberOrder, String> {
+        match name {
+            "joined" => Ok(MemberOrder::Joined),
+            "alphabetical" => Ok(MemberOrder::Alphabetical),
+            "discord" => Ok(MemberOrder::Discord),
+            "self-first" => Ok(MemberOrder::SelfFirst),
+            "recent-speaker" => Ok(MemberOrder::RecentSpeaker),
+            _ => Err(format!(
+                "Unknown member order `{}`, expected joined, alphabetical, discord, self-first or recent-speaker",
+                name
+            )),
+        }
+    }
+}
+
+impl MemberOrder {
+    // Falls back to join order, with a warning, if the variable makes no sense
+    #[allow(dead_code)]
+    pub fn from_env() -> MemberOrder {
+        match env::var("DISCERN_MEMBER_ORDER") {
+            Ok(name) => name.parse().unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                MemberOrder::default()
+            }),
+            Err(_) => MemberOrder::default(),
+        }
+    }
+}
+
+// Who overlays show. Picked with DISCERN_OVERLAY_MODE
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverlayMode {
+    // The channel name and everyone in it
+    #[default]
+    Channel,
+    // Just our own row, for a compact overlay
+    MeOnly,
+}
+
+impl FromStr for OverlayMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<OverlayMode, String> {
+        match name {
+            "channel" => Ok(OverlayMode::Channel),
+            "me-only" => Ok(OverlayMode::MeOnly),
+            _ => Err(format!(
+                "Unknown overlay mode `{}`, expected channel or me-only",
+                name
+            )),
+        }
+    }
+}
+
+impl OverlayMode {
+    // Falls back to the whole channel, with a warning, if the variable makes no sense
+    #[allow(dead_code)]
+    pub fn from_env() -> OverlayMode {
+        match env::var("DISCERN_OVERLAY_MODE") {
+            Ok(name) => name.parse().unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                OverlayMode::default()
+            }),
+            Err(_) => OverlayMode::default(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn shows_header(self) -> bool {
+        self == OverlayMode::Channel
+    }
+}
+
+// One person in the channel, as handed to renderers
+#[derive(Debug, Clone, Copy)]
+pub struct Member<'a> {
+    pub id: &'a String,
+    pub user: &'a DiscordUserData,
+    pub voice_state: &'a VoiceStateData,
+}
+
+impl Member<'_> {
+    pub fn display_name(&self) -> &String {
+        self.voice_state
+            .nick
+            .as_ref()
+            .unwrap_or(&self.user.username)
+    }
+}
+
+// A single change between two consecutive ConnStates
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StateEvent {
+    ChannelChanged { channel_id: Option<String> },
+    UserJoined { user_id: String },
+    UserLeft { user_id: String },
+    SpeakingStarted { user_id: String },
+    SpeakingStopped { user_id: String },
+    MuteChanged { user_id: String, mute: bool },
+    DeafChanged { user_id: String, deaf: bool },
+    // The connection to Discord ended. Follows the events emptying the state
+    Disconnected,
+}
+
+impl StateEvent {
+    #[allow(dead_code)]
+    pub fn user_id(&self) -> Option<&String> {
+        match self {
+            StateEvent::ChannelChanged { .. } | StateEvent::Disconnected => None,
+            StateEvent::UserJoined { user_id }
+            | StateEvent::UserLeft { user_id }
+            | StateEvent::SpeakingStarted { user_id }
+            | StateEvent::SpeakingStopped { user_id }
+            | StateEvent::MuteChanged { user_id, .. }
+            | StateEvent::DeafChanged { user_id, .. } => Some(user_id),
+        }
+    }
+}
+
+// How far along the connection to Discord is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionPhase {
+    Disconnected,
+    Connecting,
+    Authorizing,
+    Authenticated,
+    Subscribed,
+}
+
+impl ConnectionPhase {
+    // Commands can be sent from here on
+    pub fn is_ready(self) -> bool {
+        matches!(
+            self,
+            ConnectionPhase::Authenticated | ConnectionPhase::Subscribed
+        )
+    }
+}
+
+impl fmt::Display for ConnectionPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionPhase::Disconnected => "Waiting for Discord",
+            ConnectionPhase::Connecting => "Connecting to Discord",
+            ConnectionPhase::Authorizing => "Authorizing with Discord",
+            ConnectionPhase::Authenticated => "Authenticated",
+            ConnectionPhase::Subscribed => "Connected",
+        })
+    }
+}
+
+// Average ping in ms above which the voice link counts as degraded
+pub const DEGRADED_PING: f64 = 250.0;
+// Shown on overlays while it is
+pub const DEGRADED_TEXT: &str = "Voice connection degraded";
+
+// Voice server link, as reported by VOICE_CONNECTION_STATUS
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VoiceConnectionData {
+    // `VOICE_CONNECTED`, `NO_ROUTE`, `ICE_CHECKING` etc
+    pub state: String,
+    pub hostname: Option<String>,
+    pub last_ping: Option<f64>,
+    pub average_ping: Option<f64>,
+}
+
+// State of both the RPC link and the voice link. Kept out of ConnState as pings
+// change every few seconds and should not cause overlays to redraw
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectionHealth {
+    pub phase: ConnectionPhase,
+    // URL of the Discord we reached, None until a port answers
+    pub endpoint: Option<String>,
+    pub voice: Option<VoiceConnectionData>,
+}
+
+impl Default for ConnectionHealth {
+    fn default() -> ConnectionHealth {
+        ConnectionHealth::new()
+    }
+}
+
+impl ConnectionHealth {
+    pub fn new() -> ConnectionHealth {
+        ConnectionHealth {
+            phase: ConnectionPhase::Disconnected,
+            endpoint: None,
+            voice: None,
+        }
+    }
+
+    // Worth warning the user about
+    #[allow(dead_code)]
+    pub fn is_degraded(&self) -> bool {
+        match &self.voice {
+            Some(voice) => {
+                let connected = matches!(
+                    voice.state.as_str(),
+                    "DISCONNECTED" | "CONNECTED" | "VOICE_CONNECTED"
+                );
+                !connected || voice.average_ping.is_some_and(|ping| ping > DEGRADED_PING)
+            }
+            None => false,
+        }
+    }
+
+    // Line for overlays to show above the user list, if anything is wrong
+    #[allow(dead_code)]
+    pub fn warning(&self) -> Option<String> {
+        if !self.phase.is_ready() {
+            Some(self.phase.to_string())
+        } else if self.is_degraded() {
+            Some(DEGRADED_TEXT.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConnState {
     pub user_id: Option<String>,
+    // Our profile and own voice flags, once authenticated
+    #[serde(default)]
+    pub self_user: Option<SelfUserData>,
     pub voice_channel: Option<String>,
+    // Details of `voice_channel`, once Discord has sent them
+    #[serde(default)]
+    pub channel: Option<VoiceChannelData>,
+    // Every guild we're in, by ID
+    #[serde(default)]
+    pub guilds: HashMap<String, GuildData>,
     pub users: HashMap<String, DiscordUserData>,
     pub voice_states: HashMap<String, VoiceStateData>,
+    pub voice_settings: Option<VoiceSettingsData>,
+    // Text channel whose messages we follow
+    #[serde(default)]
+    pub text_channel: Option<String>,
+    // Its most recent messages, oldest first, at most MESSAGE_LIMIT
+    #[serde(default)]
+    pub messages: VecDeque<TextMessageData>,
+    // Counts up as members join and speak, to order them by. Internal, not part of snapshots
+    #[serde(skip)]
+    pub tick: u64,
 }
 
 pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
@@ -37,7 +497,13 @@ pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
 impl Hash for ConnState {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.user_id.hash(state);
+        self.self_user.hash(state);
         self.voice_channel.hash(state);
+        self.channel.hash(state);
+        for (id, guild) in self.guilds.clone() {
+            id.hash(state);
+            guild.hash(state);
+        }
         for (id, user) in self.users.clone() {
             id.hash(state);
             user.hash(state);
@@ -46,6 +512,15 @@ impl Hash for ConnState {
             id.hash(state);
             voice_state.hash(state);
         }
+        self.voice_settings.hash(state);
+        self.text_channel.hash(state);
+        self.messages.hash(state);
+    }
+}
+
+impl Default for ConnState {
+    fn default() -> ConnState {
+        ConnState::new()
     }
 }
 
@@ -53,16 +528,26 @@ impl ConnState {
     pub fn new() -> ConnState {
         ConnState {
             user_id: None,
+            self_user: None,
             voice_channel: None,
+            channel: None,
+            guilds: HashMap::new(),
             users: HashMap::new(),
             voice_states: HashMap::new(),
+            voice_settings: None,
+            text_channel: None,
+            messages: VecDeque::new(),
+            tick: 0,
         }
     }
 
     #[allow(dead_code)]
     pub fn replace_self(&mut self, new: ConnState) {
         self.user_id = new.user_id.clone();
+        self.self_user = new.self_user.clone();
         self.voice_channel = new.voice_channel.clone();
+        self.channel = new.channel.clone();
+        self.guilds = new.guilds.clone();
         self.users.clear();
         for (key, val) in new.users.iter() {
             self.users.insert(key.clone(), val.clone());
@@ -71,12 +556,237 @@ impl ConnState {
         for (key, val) in new.voice_states.iter() {
             self.voice_states.insert(key.clone(), val.clone());
         }
+        self.voice_settings = new.voice_settings.clone();
+        self.text_channel = new.text_channel.clone();
+        self.messages = new.messages.clone();
+        self.tick = new.tick;
+    }
+
+    pub fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    // Everyone in the channel, sorted the same way however often it's asked.
+    // Ties fall back to join order, then user ID
+    pub fn members(&self, order: MemberOrder) -> Vec<Member<'_>> {
+        let mut members: Vec<Member<'_>> = self
+            .voice_states
+            .iter()
+            .filter_map(|(id, voice_state)| {
+                self.users.get(id).map(|user| Member {
+                    id,
+                    user,
+                    voice_state,
+                })
+            })
+            .collect();
+        members.sort_by_key(|member| (member.voice_state.joined, member.id.clone()));
+        match order {
+            MemberOrder::Joined => {}
+            MemberOrder::Alphabetical => {
+                members.sort_by_cached_key(|member| member.display_name().to_lowercase())
+            }
+            MemberOrder::Discord => {
+                members.sort_by_key(|member| member.voice_state.position.unwrap_or(usize::MAX))
+            }
+            MemberOrder::SelfFirst => {
+                members.sort_by_key(|member| Some(member.id) != self.user_id.as_ref())
+            }
+            MemberOrder::RecentSpeaker => {
+                members.sort_by_key(|member| Reverse(member.voice_state.last_spoke))
+            }
+        }
+        members
+    }
+
+    // Members an overlay in `mode` should draw
+    #[allow(dead_code)]
+    pub fn shown_members(&self, mode: OverlayMode, order: MemberOrder) -> Vec<Member<'_>> {
+        let mut members = self.members(order);
+        if mode == OverlayMode::MeOnly {
+            members.retain(|member| self.is_self(member.id));
+        }
+        members
+    }
+
+    pub fn is_self(&self, user_id: &str) -> bool {
+        self.self_user
+            .as_ref()
+            .is_some_and(|self_user| self_user.user.id == user_id)
+    }
+
+    // Messages still worth drawing at `now`, oldest first
+    #[allow(dead_code)]
+    pub fn visible_messages(&self, now: u64, fade: Option<Duration>) -> Vec<&TextMessageData> {
+        self.messages
+            .iter()
+            .filter(|message| message.opacity(now, fade) > 0.0)
+            .collect()
+    }
+
+    // When the next message on show starts fading out, in now_ms() time. `now` while one
+    // already is, None if none ever will
+    #[allow(dead_code)]
+    pub fn fade_due(&self, now: u64, fade: Option<Duration>) -> Option<u64> {
+        let fade_ms = fade?.as_millis() as u64;
+        self.messages
+            .iter()
+            .filter(|message| message.opacity(now, fade) > 0.0)
+            .map(|message| (message.received + fade_ms - fade_ms / 4).max(now))
+            .min()
+    }
+
+    // Guild the current voice channel belongs to, if we know it
+    #[allow(dead_code)]
+    pub fn guild(&self) -> Option<&GuildData> {
+        let guild_id = self.channel.as_ref()?.guild_id.as_ref()?;
+        self.guilds.get(guild_id)
+    }
+
+    // Name to show for a user, preferring their nickname
+    #[allow(dead_code)]
+    pub fn display_name(&self, user_id: &String) -> Option<String> {
+        let nick = self
+            .voice_states
+            .get(user_id)
+            .and_then(|voice_state| voice_state.nick.clone());
+        nick.or_else(|| self.users.get(user_id).map(|user| user.username.clone()))
+    }
+
+    // Everything that changed going from `old` to this state
+    #[allow(dead_code)]
+    pub fn changes_since(&self, old: &ConnState) -> Vec<StateEvent> {
+        let mut events = vec![];
+        if self.voice_channel != old.voice_channel {
+            events.push(StateEvent::ChannelChanged {
+                channel_id: self.voice_channel.clone(),
+            });
+        }
+        for member in old.members(MemberOrder::Joined) {
+            if !self.voice_states.contains_key(member.id) {
+                events.push(StateEvent::UserLeft {
+                    user_id: member.id.clone(),
+                });
+            }
+        }
+        for member in self.members(MemberOrder::Joined) {
+            let (id, voice_state) = (member.id, member.voice_state);
+            let user_id = id.clone();
+            match old.voice_states.get(id) {
+                None => {
+                    events.push(StateEvent::UserJoined {
+                        user_id: user_id.clone(),
+                    });
+                    if voice_state.talking {
+                        events.push(StateEvent::SpeakingStarted { user_id });
+                    }
+                }
+                Some(previous) => {
+                    if voice_state.talking != previous.talking {
+                        events.push(match voice_state.talking {
+                            true => StateEvent::SpeakingStarted {
+                                user_id: user_id.clone(),
+                            },
+                            false => StateEvent::SpeakingStopped {
+                                user_id: user_id.clone(),
+                            },
+                        });
+                    }
+                    if voice_state.is_muted() != previous.is_muted() {
+                        events.push(StateEvent::MuteChanged {
+                            user_id: user_id.clone(),
+                            mute: voice_state.is_muted(),
+                        });
+                    }
+                    if voice_state.is_deafened() != previous.is_deafened() {
+                        events.push(StateEvent::DeafChanged {
+                            user_id,
+                            deaf: voice_state.is_deafened(),
+                        });
+                    }
+                }
+            }
+        }
+        events
     }
 
     pub fn clear(&mut self) {
         self.user_id = None;
+        self.self_user = None;
         self.voice_channel = None;
+        self.channel = None;
+        self.guilds.clear();
         self.users.clear();
         self.voice_states.clear();
+        self.voice_settings = None;
+        self.text_channel = None;
+        self.messages.clear();
+        self.tick = 0;
+    }
+}
+
+// Version of the JSON form of ConnState. Bumped whenever a field is renamed, removed or
+// changes meaning. New fields don't bump it, readers should skip anything they don't know
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Json(serde_json::Error),
+    // Written by a newer discern than this one
+    Version(u64),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Json(err) => write!(f, "Invalid snapshot: {}", err),
+            SnapshotError::Version(version) => write!(
+                f,
+                "Snapshot schema version {} is newer than {}",
+                version, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+// ConnState as written to disk or handed to other processes. See "State snapshots" in README.md
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    // IDs of everyone in `voice_states`, in the order the frontend shows them
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(flatten)]
+    pub state: ConnState,
+}
+
+#[allow(dead_code)]
+impl Snapshot {
+    pub fn new(state: ConnState, order: MemberOrder) -> Snapshot {
+        let members = state
+            .members(order)
+            .iter()
+            .map(|member| member.id.clone())
+            .collect();
+        Snapshot {
+            version: SCHEMA_VERSION,
+            members,
+            state,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("ConnState always serializes")
+    }
+
+    pub fn from_json(raw: &str) -> Result<Snapshot, SnapshotError> {
+        let value: Value = serde_json::from_str(raw).map_err(SnapshotError::Json)?;
+        // Check before anything else, a newer layout may not parse at all
+        let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+        if version > SCHEMA_VERSION as u64 {
+            return Err(SnapshotError::Version(version));
+        }
+        serde_json::from_value(value).map_err(SnapshotError::Json)
     }
 }
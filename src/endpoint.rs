@@ -0,0 +1,76 @@
+// Where to find the local Discord RPC server.
+// Every field can be overridden from the environment:
+//   DISCERN_RPC_HOST       default 127.0.0.1
+//   DISCERN_RPC_PORT       default scans 6463 - 6472
+//   DISCERN_RPC_CLIENT_ID  default is the StreamKit client
+//   DISCERN_RPC_ORIGIN     default https://streamkit.discord.com
+use std::env;
+use std::ops::RangeInclusive;
+
+// Each Discord client (stable, PTB, Canary, Flatpak...) takes the first free port of these
+pub const PORT_RANGE: RangeInclusive<u16> = 6463..=6472;
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_CLIENT_ID: &str = "207646673902501888";
+pub const DEFAULT_ORIGIN: &str = "https://streamkit.discord.com";
+
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub host: String,
+    // Only try this port instead of scanning PORT_RANGE
+    pub port: Option<u16>,
+    pub client_id: String,
+    pub origin: String,
+}
+
+impl Default for Endpoint {
+    fn default() -> Endpoint {
+        Endpoint {
+            host: DEFAULT_HOST.to_string(),
+            port: None,
+            client_id: DEFAULT_CLIENT_ID.to_string(),
+            origin: DEFAULT_ORIGIN.to_string(),
+        }
+    }
+}
+
+impl Endpoint {
+    pub fn from_env() -> Endpoint {
+        let defaults = Endpoint::default();
+        let port = match env::var("DISCERN_RPC_PORT") {
+            Ok(port) => match port.parse::<u16>() {
+                Ok(port) => Some(port),
+                Err(_) => {
+                    eprintln!("Ignoring DISCERN_RPC_PORT, not a port number: {}", port);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        Endpoint {
+            host: env::var("DISCERN_RPC_HOST").unwrap_or(defaults.host),
+            port,
+            client_id: env::var("DISCERN_RPC_CLIENT_ID").unwrap_or(defaults.client_id),
+            origin: env::var("DISCERN_RPC_ORIGIN").unwrap_or(defaults.origin),
+        }
+    }
+
+    // Whether this is where Discord normally listens, rather than an override
+    pub fn is_default_location(&self) -> bool {
+        self.host == DEFAULT_HOST && self.port.is_none()
+    }
+
+    // Ports to try, in order
+    pub fn ports(&self) -> Vec<u16> {
+        match self.port {
+            Some(port) => vec![port],
+            None => PORT_RANGE.collect(),
+        }
+    }
+
+    pub fn url(&self, port: u16) -> String {
+        format!(
+            "ws://{}:{}/?v=1&client_id={}",
+            self.host, port, self.client_id
+        )
+    }
+}
@@ -6,7 +6,8 @@ extern crate xcb;
 
 use cairo::{Antialias, Context, FillRule, FontSlant, FontWeight, ImageSurface, Operator};
 use cairorender::DiscordAvatarRaw;
-use data::ConnState;
+use clap::command;
+use data::{ConnState, MemberOrder, OverlayMode};
 use futures::lock::Mutex;
 use futures::stream::StreamExt;
 use futures_util::SinkExt;
@@ -19,12 +20,19 @@ use xcb::randr::Event::ScreenChangeNotify;
 use xcb::{x, Xid};
 
 mod cairorender;
+mod client;
 mod core;
 mod data;
+mod endpoint;
 mod macros;
+mod protocol;
+mod recording;
+mod token;
 
 #[tokio::main]
 async fn main() {
+    let matches = command!().arg(recording::replay_arg()).get_matches();
+
     // Avatar to main thread
     let (mut avatar_request_sender, avatar_request_recv) =
         futures::channel::mpsc::channel::<ConnState>(10);
@@ -38,15 +46,14 @@ async fn main() {
     let event_sender: Arc<Mutex<futures_channel::mpsc::Sender<ConnState>>> =
         Arc::new(Mutex::new(event_sender));
 
-    // Main thread messages to Websocket output
-    let (msg_sender, msg_recv) = futures::channel::mpsc::channel::<String>(10);
-    let _msg_sender = Arc::new(Mutex::new(msg_sender));
-    let msg_recv = Arc::new(Mutex::new(msg_recv));
-
     // Start a thread for connection
     let connector_event_sender = event_sender.clone();
-    let connector_msg_recv = msg_recv.clone();
-    core::connector(connector_event_sender.clone(), connector_msg_recv.clone()).await;
+    let discord = core::connector_with(
+        connector_event_sender.clone(),
+        core::ConnectorConfig::from_args(&matches),
+    )
+    .await;
+    let mut health = discord.health();
 
     // Start a thread for avatars
     cairorender::avatar_downloader(avatar_done_sender, avatar_request_recv).await;
@@ -259,12 +266,19 @@ async fn main() {
         conn.wait_for_reply(cookies.0).unwrap().atom()
     };
     let mut state = ConnState::new();
+    let mut warning = health.borrow().warning();
+    let order = MemberOrder::from_env();
+    let mode = OverlayMode::from_env();
+    // How long messages stay up
+    let fade = data::message_fade_from_env();
+    let mut was_fading = false;
     loop {
         let xloop = async { conn.poll_for_event() };
-        let (xevent, threadevent, avatarevent) = select! {
-            x = xloop => ( Some(x), None, None),
-            x = event_recv.next() => (None,Some(x),None),
-            x = avatar_done_recv.next() => (None, None, Some(x)),
+        let (xevent, threadevent, avatarevent, healthevent) = select! {
+            x = xloop => ( Some(x), None, None, None),
+            x = event_recv.next() => (None,Some(x),None, None),
+            x = avatar_done_recv.next() => (None, None, Some(x), None),
+            Ok(()) = health.changed() => (None, None, None, Some(health.borrow().warning())),
         };
         let mut sleep = 100;
         let mut redraw = false;
@@ -325,12 +339,18 @@ async fn main() {
             Some(Some(avatardata)) => {
                 match avatardata.raw {
                     Some(raw) => {
-                        let surface = ImageSurface::create_from_png(&mut Cursor::new(raw))
-                            .expect("Error processing user avatar");
+                        // A bad download, e.g. an HTTP error page, is skipped like a missing one
+                        let surface = match ImageSurface::create_from_png(&mut Cursor::new(raw)) {
+                            Ok(surface) => Some(surface),
+                            Err(err) => {
+                                println!("Unable to decode image {} : {}", avatardata.key, err);
+                                None
+                            }
+                        };
                         avatar_list
                             .lock()
                             .unwrap()
-                            .insert(avatardata.key.clone(), Some(surface));
+                            .insert(avatardata.key.clone(), surface);
                     }
                     None => {
                         println!("Raw is None for user id {}", avatardata.key);
@@ -347,12 +367,29 @@ async fn main() {
             Some(None) => {}
             None => {}
         }
+        // Pings change often, only redraw when the warning should change
+        if let Some(now_warning) = healthevent {
+            if now_warning != warning {
+                warning = now_warning;
+                redraw = true;
+                sleep = 0;
+            }
+        }
+        // Keep redrawing while messages fade out, and once more after the last is gone
+        let now = data::now_ms();
+        let fading = state.fade_due(now, fade) == Some(now);
+        if fading || was_fading {
+            redraw = true;
+        }
+        was_fading = fading;
         if redraw {
             let cr = create_cairo_context(&conn, &screen, &win, window_width, window_height);
 
-            let should_show = state.users.len() > 0;
+            let should_show = state.users.len() > 0
+                || !state.visible_messages(now, fade).is_empty()
+                || warning.is_some();
             set_as_overlay(&conn, &win, &atom_overlay, should_show);
-            draw_overlay!(&cr, avatar_list, state);
+            draw_overlay!(&cr, avatar_list, state, mode, order, fade, warning);
         }
         if sleep > 0 {
             tokio::time::sleep(tokio::time::Duration::from_millis(sleep)).await;
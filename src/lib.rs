@@ -0,0 +1,10 @@
+// Connection and state code shared by every frontend, for tests and anything
+// else wanting to embed discern. The binaries include these modules themselves
+pub mod client;
+pub mod core;
+pub mod data;
+pub mod endpoint;
+pub mod macros;
+pub mod protocol;
+pub mod recording;
+pub mod token;
@@ -1,72 +1,57 @@
-// Send raw value over websocket
+// Send raw value over websocket. Gives up on the rest if the socket is gone,
+// the read loop notices and reconnects
 #[macro_export]
 macro_rules! send_socket {
     ($writer: expr, $value: expr) => {
         for packet in $value.iter() {
-            $writer
+            if let Err(err) = $writer
                 .lock()
                 .await
                 .send(Message::Text(packet.to_string() + "\n"))
                 .await
-                .unwrap();
-        }
-    };
-}
-
-// Send raw value locally to socket thread
-#[macro_export]
-macro_rules! send_mpsc {
-    ($writer: expr, $value: expr) => {
-        for packet in $value.iter() {
-            $writer
-                .lock()
-                .await
-                .try_send(packet.to_string())
-                .expect("Unable to send packet");
+            {
+                eprintln!("{}", $crate::core::ConnectorError::Socket(err));
+                break;
+            }
         }
     };
 }
 
 // First packet to send. Explains what scopes the app will need
 #[macro_export]
-macro_rules! packet_auth{
+macro_rules! packet_auth {
     ($auth_code: expr) => {
-        [json!({
-            "cmd": "AUTHORIZE",
-            "args": {
-                "client_id": $auth_code,
-                "scopes": ["rpc", "messages.read", "rpc.notifications.read"],
-                "prompt": "none",
+        [$crate::protocol::Request::new(
+            $crate::protocol::Command::Authorize {
+                client_id: $auth_code.to_string(),
+                scopes: vec![
+                    "rpc".to_string(),
+                    "messages.read".to_string(),
+                    "rpc.notifications.read".to_string(),
+                ],
+                prompt: "none".to_string(),
             },
-            "nonce": "deadbeef"
-        })]
-    }
+        )]
+    };
 }
 
 // Second, with an access token to authenticate the app
 #[macro_export]
-macro_rules! packet_auth2{
+macro_rules! packet_auth2 {
     ($token: expr) => {
-        [json!({
-            "cmd": "AUTHENTICATE",
-            "args": {
-                "access_token": $token,
+        [$crate::protocol::Request::new(
+            $crate::protocol::Command::Authenticate {
+                access_token: $token.to_string(),
             },
-            "nonce": "deadbeef"
-        })]
-    }
+        )]
+    };
 }
 
 // Request a list of all guilds the user is in
 #[macro_export]
 macro_rules! packet_req_all_guilds{
     {} => {
-        [json!({
-            "cmd": "GET_GUILDS",
-            "args": {
-            },
-            "nonce": "deadbeef"
-        })]
+        [$crate::protocol::Request::new($crate::protocol::Command::GetGuilds {})]
     }
 }
 
@@ -74,25 +59,17 @@ macro_rules! packet_req_all_guilds{
 #[macro_export]
 macro_rules! packet_req_selected_voice{
     {} => {
-        [json!({
-            "cmd": "GET_SELECTED_VOICE_CHANNEL",
-            "args": {
-            },
-            "nonce": "deadbeef"
-        })]
+        [$crate::protocol::Request::new(
+            $crate::protocol::Command::GetSelectedVoiceChannel {},
+        )]
     }
 }
 
 // Subscribe to event callbacks
 #[macro_export]
 macro_rules! packet_sub{
-    {$event: expr, $args: expr, $nonce: expr} =>{
-        json!({
-            "cmd": "SUBSCRIBE",
-            "args": $args,
-            "evt": $event,
-            "nonce": $nonce
-        })
+    {$event: expr, $channel: expr} =>{
+        $crate::protocol::Request::subscribe($event, $channel)
     }
 }
 
@@ -100,8 +77,18 @@ macro_rules! packet_sub{
 #[macro_export]
 macro_rules! packet_sub_server{
     {} => {
-        [packet_sub!("VOICE_CHANNEL_SELECT", json!({}), "VOICE_CHANNEL_SELECT"),
-        packet_sub!("VOICE_CONNECTION_STATUS", json!({}), "VOICE_CONNECTION_STATUS")]
+        [packet_sub!("VOICE_CHANNEL_SELECT", None),
+        packet_sub!("VOICE_CONNECTION_STATUS", None),
+        packet_sub!("VOICE_SETTINGS_UPDATE", None),
+        packet_sub!("GUILD_CREATE", None)]
+    }
+}
+
+// Subscribe to events about one guild
+#[macro_export]
+macro_rules! packet_sub_guild{
+    {$guild: expr} => {
+        [$crate::protocol::Request::subscribe_guild("GUILD_STATUS", $guild.to_string())]
     }
 }
 
@@ -109,7 +96,7 @@ macro_rules! packet_sub_server{
 #[macro_export]
 macro_rules! packet_sub_channel{
     {$event: expr, $channel: expr} => {
-        packet_sub!($event, json!({"channel_id":$channel}), $channel)
+        packet_sub!($event, Some($channel.to_string()))
     }
 }
 
@@ -121,7 +108,28 @@ macro_rules! packet_sub_voice_channel{
         packet_sub_channel!("VOICE_STATE_UPDATE", $channel),
         packet_sub_channel!("VOICE_STATE_DELETE", $channel),
         packet_sub_channel!("SPEAKING_START", $channel),
-        packet_sub_channel!("SPEAKING_STOP", $channel)]
+        packet_sub_channel!("SPEAKING_STOP", $channel),
+        packet_sub_channel!("CHANNEL_UPDATE", $channel)]
+    }
+}
+
+// Subscribe to messages in a text channel
+#[macro_export]
+macro_rules! packet_sub_text_channel{
+    {$channel: expr} => {
+        [packet_sub_channel!("MESSAGE_CREATE", $channel),
+        packet_sub_channel!("MESSAGE_UPDATE", $channel),
+        packet_sub_channel!("MESSAGE_DELETE", $channel)]
+    }
+}
+
+// Stop receiving messages from a text channel
+#[macro_export]
+macro_rules! packet_unsub_text_channel{
+    {$channel: expr} => {
+        [$crate::protocol::Request::unsubscribe("MESSAGE_CREATE", Some($channel.to_string())),
+        $crate::protocol::Request::unsubscribe("MESSAGE_UPDATE", Some($channel.to_string())),
+        $crate::protocol::Request::unsubscribe("MESSAGE_DELETE", Some($channel.to_string()))]
     }
 }
 
@@ -129,11 +137,9 @@ macro_rules! packet_sub_voice_channel{
 #[macro_export]
 macro_rules! packet_req_devices{
     {} =>{
-        [json!({
-            "cmd": "GET_VOICE_SETTINGS",
-            "args": {},
-            "nonce": "deadbeef"
-        })]
+        [$crate::protocol::Request::new(
+            $crate::protocol::Command::GetVoiceSettings {},
+        )]
     }
 }
 
@@ -141,33 +147,109 @@ macro_rules! packet_req_devices{
 #[macro_export]
 macro_rules! packet_set_channel{
     {$channel: expr} => {
-        [json!({
-            "cmd": "SELECT_VOICE_CHANNEL",
-            "args": {
-                "channel_id": $channel,
-                "force": true
+        [$crate::protocol::Request::new(
+            $crate::protocol::Command::SelectVoiceChannel {
+                channel_id: $channel.to_string(),
+                force: true,
             },
-            "nonce": "deadbeef"
-        })]
+        )]
     }
 }
 
 // Request we change the users device setting (mute, deaf etc)
 #[macro_export]
 macro_rules! packet_set_devices{
-    {$dev: expr, $value: expr, $nonce: expr} =>{
-        [json!({
-            "cmd": "SET_VOICE_SETTINGS",
-            "args": {$dev:$value},
-            "nonce": $nonce
-        })]
+    {$dev: expr, $value: expr} =>{
+        [$crate::protocol::Request::new(
+            $crate::protocol::Command::SetVoiceSettings(serde_json::json!({$dev:$value})),
+        )]
+    }
+}
+
+// Request we change how another user sounds to us (volume, pan, local mute)
+#[macro_export]
+macro_rules! packet_set_user_voice {
+    {$settings: expr} => {
+        [$crate::protocol::Request::new(
+            $crate::protocol::Command::SetUserVoiceSettings($settings),
+        )]
+    };
+}
+
+// Cairo helper. Warning above the user list, see ConnectionHealth::warning
+#[macro_export]
+macro_rules! draw_warning{
+    {$ctx: expr, $edge: expr, $text: expr} => {
+        let ext = $ctx.text_extents($text).unwrap();
+        $ctx.set_source_rgba(0.0, 0.0, 0.0, 0.4);
+        $ctx.rectangle(0.0, $edge, ext.width + $edge * 2.0, ext.height + $edge * 2.0);
+        $ctx.fill().expect("Unable to fill");
+        $ctx.set_source_rgba(1.0, 0.6, 0.0, 1.0);
+        $ctx.move_to($edge, $edge * 2.0 + ext.height);
+        $ctx.show_text($text).expect("unable to draw text");
+    }
+}
+
+// Longest message drawn on overlays, in characters
+#[allow(dead_code)]
+pub const MESSAGE_CHARS: usize = 80;
+// How often overlays redraw while messages fade out
+#[allow(dead_code)]
+pub const FADE_REDRAW: std::time::Duration = std::time::Duration::from_millis(200);
+
+// Cairo helper. One text channel message below the user list
+#[macro_export]
+macro_rules! draw_message{
+    {$ctx: expr, $edge: expr, $y: expr, $line_height: expr, $text: expr, $opacity: expr} => {
+        let ext = $ctx.text_extents($text).unwrap();
+        $ctx.set_source_rgba(0.0, 0.0, 0.0, 0.4 * $opacity);
+        $ctx.rectangle(
+            0.0,
+            $y + ($line_height / 2.0) - (ext.height / 2.0) - $edge,
+            ext.width + $edge * 2.0,
+            ext.height + $edge * 2.0,
+        );
+        $ctx.fill().expect("Unable to fill");
+        $ctx.set_source_rgba(1.0, 1.0, 1.0, $opacity);
+        $ctx.move_to($edge, $y + ($line_height / 2.0) + (ext.height / 2.0));
+        $ctx.show_text($text).expect("unable to draw text");
+    }
+}
+
+// Cairo helper. Channel name on its own line above the user list
+#[macro_export]
+macro_rules! draw_header{
+    {$ctx: expr, $edge: expr, $y: expr, $line_height: expr, $title: expr, $icon: expr} => {
+        let ext = $ctx.text_extents($title).unwrap();
+        $ctx.set_source_rgba(0.0, 0.0, 0.0, 0.4);
+        $ctx.rectangle(
+            $line_height,
+            $y + ($line_height / 2.0) - (ext.height / 2.0) - $edge,
+            ext.width + $edge * 2.0,
+            ext.height + $edge * 2.0,
+        );
+        $ctx.fill().expect("Unable to fill");
+        $ctx.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+        $ctx.move_to($line_height + $edge, $y + ($line_height / 2.0) + (ext.height / 2.0));
+        $ctx.show_text($title).expect("unable to draw text");
+        // Guild icon sits where members have their avatar
+        if let Some(img) = $icon {
+            $ctx.save().expect("Unable to save cairo state");
+            $ctx.translate(0.0, $y);
+            $ctx.scale($line_height, $line_height);
+            $ctx.scale(1.0 / img.width() as f64, 1.0 / img.height() as f64);
+            $ctx.set_source_surface(img, 0.0, 0.0).unwrap();
+            $ctx.rectangle(0.0, 0.0, img.width() as f64, img.height() as f64);
+            $ctx.fill().unwrap();
+            $ctx.restore().expect("Unable to restore cairo state");
+        }
     }
 }
 
 // Cairo helper
 #[macro_export]
 macro_rules! draw_overlay_gtk{
-    {$window: expr, $ctx: expr, $avatar_list:expr, $state: expr} => {
+    {$window: expr, $ctx: expr, $avatar_list:expr, $state: expr, $mode: expr, $order: expr, $fade: expr, $warning: expr} => {
         let reg = Region::create();
         reg.union_rectangle(& RectangleInt{
             x: 0,
@@ -190,94 +272,139 @@ macro_rules! draw_overlay_gtk{
         let mut y = 50.0;
         $ctx.set_operator(Operator::Over);
 
-        if state.users.len() > 0 {
-            for (key, user) in state.users {
-                match state.voice_states.get(&key) {
-                    Some(voice_state) => {
-                        let mut name = user.username.clone();
-                        match &voice_state.nick {
-                            Some(nick) => {
-                                name = nick.clone();
-                            }
-                            None => {}
-                        }
-                        if voice_state.talking {
-                            $ctx.set_source_rgba(0.0, 0.4, 0.0, 0.6);
-                        } else {
-                            $ctx.set_source_rgba(0.0, 0.0, 0.0, 0.4);
-                        }
-                        let ext = $ctx.text_extents(&name).unwrap();
-                        // Draw border around text
-                        $ctx.rectangle(
-                            line_height,
-                            y + (line_height / 2.0) - (ext.height / 2.0) - edge,
-                            ext.width + edge * 2.0,
-                            ext.height + edge * 2.0,
-                        );
-                        $ctx.fill().expect("Unable to fill");
-                        $ctx.move_to(
-                            line_height + edge,
-                            y + (line_height / 2.0) + (ext.height / 2.0),
-                        );
-                        // Draw border into XShape
-                        reg.union_rectangle(& RectangleInt{
-                            x:line_height as i32 ,
-                            y:(y + (line_height / 2.0) - (ext.height / 2.0) - edge) as i32,
-                            width: (ext.width + edge * 2.0) as i32,
-                            height: (ext.height + edge * 2.0) as i32
-                        }).expect("Unable to add rectangle to XShape");
-                        reg.union_rectangle(& RectangleInt{
-                            x:0,
-                            y:y as i32 ,
-                            width:line_height as i32,
-                            height:line_height as i32
-                        }).expect("Unable to add rectangle to XShape");
-
-                        if voice_state.talking {
-                            $ctx.set_source_rgba(0.0, 1.0, 0.0, 1.0);
-                        } else {
-                            $ctx.set_source_rgba(1.0, 1.0, 1.0, 1.0);
-                        }
-                        $ctx.show_text(&name).expect("unable to draw text");
-
-                        let avatar_list = $avatar_list.lock().unwrap();
-                        match user.avatar{
-                            Some(_avatar) => {
-                                match avatar_list.get(&user.id){
-                                    Some(img)=>{
-                                        match img{
-                                            Some(img) =>{
-                                                $ctx.save().expect("Unable to save cairo state");
-                                                $ctx.translate(0.0, y);
-                                                $ctx.scale(line_height, line_height);
-                                                $ctx.scale(1.0 / img.width() as f64, 1.0 / img.height() as f64);
-                                                $ctx.set_source_surface(img,0.0,0.0).unwrap();
-                                                $ctx.rectangle(0.0,0.0,img.width() as f64, img.height() as f64);
-                                                $ctx.fill().unwrap();
-                                                $ctx.restore().expect("Unable to restore cairo state");
-                                            }
-                                            None => {
-                                                println!("Avatar ready but None {}",user.id );
-                                            // Requested but no image (yet?) Don't draw anything more
-                                            }
-                                        }
-                                    }
-                                    None=>{
-                                    }
+        if let Some(warning) = $warning.as_deref() {
+            draw_warning!($ctx, edge, warning);
+            let ext = $ctx.text_extents(warning).unwrap();
+            reg.union_rectangle(& RectangleInt{
+                x: 0,
+                y: edge as i32,
+                width: (ext.width + edge * 2.0) as i32,
+                height: (ext.height + edge * 2.0) as i32
+            }).expect("Unable to add rectangle to XShape");
+        }
+
+        if let Some(channel) = state.channel.as_ref().filter(|_| $mode.shows_header()) {
+            let title = channel.title();
+            let avatar_list = $avatar_list.lock().unwrap();
+            let icon = state
+                .guild()
+                .and_then(|guild| guild.icon_key())
+                .and_then(|icon_key| avatar_list.get(&icon_key))
+                .and_then(|img| img.as_ref());
+            draw_header!($ctx, edge, y, line_height, &title, icon);
+            let ext = $ctx.text_extents(&title).unwrap();
+            reg.union_rectangle(& RectangleInt{
+                x: line_height as i32,
+                y: (y + (line_height / 2.0) - (ext.height / 2.0) - edge) as i32,
+                width: (ext.width + edge * 2.0) as i32,
+                height: (ext.height + edge * 2.0) as i32
+            }).expect("Unable to add rectangle to XShape");
+            if icon.is_some() {
+                reg.union_rectangle(& RectangleInt{
+                    x: 0,
+                    y: y as i32,
+                    width: line_height as i32,
+                    height: line_height as i32
+                }).expect("Unable to add rectangle to XShape");
+            }
+            y += line_height;
+        }
+
+        for member in state.shown_members($mode, $order) {
+            let user = member.user;
+            let voice_state = member.voice_state;
+            let name = member.display_name().clone();
+            if voice_state.talking {
+                $ctx.set_source_rgba(0.0, 0.4, 0.0, 0.6);
+            } else {
+                $ctx.set_source_rgba(0.0, 0.0, 0.0, 0.4);
+            }
+            let ext = $ctx.text_extents(&name).unwrap();
+            // Draw border around text
+            $ctx.rectangle(
+                line_height,
+                y + (line_height / 2.0) - (ext.height / 2.0) - edge,
+                ext.width + edge * 2.0,
+                ext.height + edge * 2.0,
+            );
+            $ctx.fill().expect("Unable to fill");
+            $ctx.move_to(
+                line_height + edge,
+                y + (line_height / 2.0) + (ext.height / 2.0),
+            );
+            // Draw border into XShape
+            reg.union_rectangle(& RectangleInt{
+                x:line_height as i32 ,
+                y:(y + (line_height / 2.0) - (ext.height / 2.0) - edge) as i32,
+                width: (ext.width + edge * 2.0) as i32,
+                height: (ext.height + edge * 2.0) as i32
+            }).expect("Unable to add rectangle to XShape");
+            reg.union_rectangle(& RectangleInt{
+                x:0,
+                y:y as i32 ,
+                width:line_height as i32,
+                height:line_height as i32
+            }).expect("Unable to add rectangle to XShape");
+
+            if voice_state.talking {
+                $ctx.set_source_rgba(0.0, 1.0, 0.0, 1.0);
+            } else {
+                $ctx.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+            }
+            $ctx.show_text(&name).expect("unable to draw text");
+
+            let avatar_list = $avatar_list.lock().unwrap();
+            match &user.avatar{
+                Some(_avatar) => {
+                    match avatar_list.get(&user.id){
+                        Some(img)=>{
+                            match img{
+                                Some(img) =>{
+                                    $ctx.save().expect("Unable to save cairo state");
+                                    $ctx.translate(0.0, y);
+                                    $ctx.scale(line_height, line_height);
+                                    $ctx.scale(1.0 / img.width() as f64, 1.0 / img.height() as f64);
+                                    $ctx.set_source_surface(img,0.0,0.0).unwrap();
+                                    $ctx.rectangle(0.0,0.0,img.width() as f64, img.height() as f64);
+                                    $ctx.fill().unwrap();
+                                    $ctx.restore().expect("Unable to restore cairo state");
+                                }
+                                None => {
+                                    println!("Avatar ready but None {}",user.id );
+                                // Requested but no image (yet?) Don't draw anything more
                                 }
-                            },
-                            None=>{}
+                            }
                         }
-                        if voice_state.deaf || voice_state.self_deaf {
-                            draw_deaf($ctx, 0.0, y, line_height);
-                        } else if voice_state.mute || voice_state.self_mute {
-                            draw_mute($ctx, 0.0, y, line_height);
+                        None=>{
                         }
                     }
-                    None => {}
-                }
-                y += line_height;
+                },
+                None=>{}
             }
+            if voice_state.deaf || voice_state.self_deaf {
+                draw_deaf($ctx, 0.0, y, line_height);
+            } else if voice_state.mute || voice_state.self_mute {
+                draw_mute($ctx, 0.0, y, line_height);
+            }
+            y += line_height;
+        }
+
+        let now = $crate::data::now_ms();
+        let messages = state.visible_messages(now, $fade);
+        if !messages.is_empty() {
+            y += line_height / 2.0;
+        }
+        for message in messages {
+            let text: String = message.summary().chars().take($crate::macros::MESSAGE_CHARS).collect();
+            draw_message!($ctx, edge, y, line_height, &text, message.opacity(now, $fade));
+            let ext = $ctx.text_extents(&text).unwrap();
+            reg.union_rectangle(& RectangleInt{
+                x: 0,
+                y: (y + (line_height / 2.0) - (ext.height / 2.0) - edge) as i32,
+                width: (ext.width + edge * 2.0) as i32,
+                height: (ext.height + edge * 2.0) as i32
+            }).expect("Unable to add rectangle to XShape");
+            y += line_height;
         }
         $window.shape_combine_region(Some(&reg));
     }
@@ -286,7 +413,7 @@ macro_rules! draw_overlay_gtk{
 // Cairo helper
 #[macro_export]
 macro_rules! draw_overlay{
-    {$ctx: expr, $avatar_list:expr, $state: expr} => {
+    {$ctx: expr, $avatar_list:expr, $state: expr, $mode: expr, $order: expr, $fade: expr, $warning: expr} => {
         // Config / Static
         let edge = 6.0;
         let line_height = 32.0;
@@ -302,83 +429,98 @@ macro_rules! draw_overlay{
         let mut y = 50.0;
         $ctx.set_operator(Operator::Over);
 
-        if state.users.len() > 0 {
-            for (key, user) in state.users {
-                match state.voice_states.get(&key) {
-                    Some(voice_state) => {
-                        let mut name = user.username.clone();
-                        match &voice_state.nick {
-                            Some(nick) => {
-                                name = nick.clone();
-                            }
-                            None => {}
-                        }
-                        if voice_state.talking {
-                            $ctx.set_source_rgba(0.0, 0.4, 0.0, 0.6);
-                        } else {
-                            $ctx.set_source_rgba(0.0, 0.0, 0.0, 0.4);
-                        }
-                        let ext = $ctx.text_extents(&name).unwrap();
-                        // Draw border around text
-                        $ctx.rectangle(
-                            line_height,
-                            y + (line_height / 2.0) - (ext.height / 2.0) - edge,
-                            ext.width + edge * 2.0,
-                            ext.height + edge * 2.0,
-                        );
-                        $ctx.fill().expect("Unable to fill");
-                        $ctx.move_to(
-                            line_height + edge,
-                            y + (line_height / 2.0) + (ext.height / 2.0),
-                        );
-
-                        if voice_state.talking {
-                            $ctx.set_source_rgba(0.0, 1.0, 0.0, 1.0);
-                        } else {
-                            $ctx.set_source_rgba(1.0, 1.0, 1.0, 1.0);
-                        }
-                        $ctx.show_text(&name).expect("unable to draw text");
-
-                        let avatar_list = $avatar_list.lock().unwrap();
-                        match user.avatar{
-                            Some(_avatar) => {
-                                match avatar_list.get(&user.id){
-                                    Some(img)=>{
-                                        match img{
-                                            Some(img) =>{
-                                                $ctx.save().expect("Unable to save cairo state");
-                                                $ctx.translate(0.0, y);
-                                                $ctx.scale(line_height, line_height);
-                                                $ctx.scale(1.0 / img.width() as f64, 1.0 / img.height() as f64);
-                                                $ctx.set_source_surface(img,0.0,0.0).unwrap();
-                                                $ctx.rectangle(0.0,0.0,img.width() as f64, img.height() as f64);
-                                                $ctx.fill().unwrap();
-                                                $ctx.restore().expect("Unable to restore cairo state");
-                                            }
-                                            None => {
-                                                println!("Requested image but no data");
-                                            // Requested but no image (yet?) Don't draw anything more
-                                            }
-                                        }
-                                    }
-                                    None=>{
-                                    }
+        if let Some(warning) = $warning.as_deref() {
+            draw_warning!($ctx, edge, warning);
+        }
+
+        if let Some(channel) = state.channel.as_ref().filter(|_| $mode.shows_header()) {
+            let avatar_list = $avatar_list.lock().unwrap();
+            let icon = state
+                .guild()
+                .and_then(|guild| guild.icon_key())
+                .and_then(|icon_key| avatar_list.get(&icon_key))
+                .and_then(|img| img.as_ref());
+            draw_header!($ctx, edge, y, line_height, &channel.title(), icon);
+            y += line_height;
+        }
+
+        for member in state.shown_members($mode, $order) {
+            let user = member.user;
+            let voice_state = member.voice_state;
+            let name = member.display_name().clone();
+            if voice_state.talking {
+                $ctx.set_source_rgba(0.0, 0.4, 0.0, 0.6);
+            } else {
+                $ctx.set_source_rgba(0.0, 0.0, 0.0, 0.4);
+            }
+            let ext = $ctx.text_extents(&name).unwrap();
+            // Draw border around text
+            $ctx.rectangle(
+                line_height,
+                y + (line_height / 2.0) - (ext.height / 2.0) - edge,
+                ext.width + edge * 2.0,
+                ext.height + edge * 2.0,
+            );
+            $ctx.fill().expect("Unable to fill");
+            $ctx.move_to(
+                line_height + edge,
+                y + (line_height / 2.0) + (ext.height / 2.0),
+            );
+
+            if voice_state.talking {
+                $ctx.set_source_rgba(0.0, 1.0, 0.0, 1.0);
+            } else {
+                $ctx.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+            }
+            $ctx.show_text(&name).expect("unable to draw text");
+
+            let avatar_list = $avatar_list.lock().unwrap();
+            match &user.avatar{
+                Some(_avatar) => {
+                    match avatar_list.get(&user.id){
+                        Some(img)=>{
+                            match img{
+                                Some(img) =>{
+                                    $ctx.save().expect("Unable to save cairo state");
+                                    $ctx.translate(0.0, y);
+                                    $ctx.scale(line_height, line_height);
+                                    $ctx.scale(1.0 / img.width() as f64, 1.0 / img.height() as f64);
+                                    $ctx.set_source_surface(img,0.0,0.0).unwrap();
+                                    $ctx.rectangle(0.0,0.0,img.width() as f64, img.height() as f64);
+                                    $ctx.fill().unwrap();
+                                    $ctx.restore().expect("Unable to restore cairo state");
+                                }
+                                None => {
+                                    println!("Requested image but no data");
+                                // Requested but no image (yet?) Don't draw anything more
                                 }
-                            },
-                            None=>{
-                                println!("Error in userdata {:?}", user);
                             }
                         }
-                        if voice_state.deaf || voice_state.self_deaf {
-                            draw_deaf($ctx, 0.0, y, line_height);
-                        } else if voice_state.mute || voice_state.self_mute {
-                            draw_mute($ctx, 0.0, y, line_height);
+                        None=>{
                         }
                     }
-                    None => {}
+                },
+                None=>{
+                    println!("Error in userdata {:?}", user);
                 }
-                y += line_height;
             }
+            if voice_state.deaf || voice_state.self_deaf {
+                draw_deaf($ctx, 0.0, y, line_height);
+            } else if voice_state.mute || voice_state.self_mute {
+                draw_mute($ctx, 0.0, y, line_height);
+            }
+            y += line_height;
+        }
+
+        let now = $crate::data::now_ms();
+        let messages = state.visible_messages(now, $fade);
+        if !messages.is_empty() {
+            y += line_height / 2.0;
+        }
+        for message in messages {
+            let text: String = message.summary().chars().take($crate::macros::MESSAGE_CHARS).collect();
+            draw_message!($ctx, edge, y, line_height, &text, message.opacity(now, $fade));
+            y += line_height;
         }
     }
 }
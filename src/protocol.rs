@@ -0,0 +1,566 @@
+// Typed view of the Discord RPC websocket protocol.
+// Shared by every binary, so not every variant is used by each of them
+#![allow(dead_code)]
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NONCE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Every outgoing packet gets its own nonce so the reply can be matched to it
+pub fn next_nonce() -> String {
+    format!("discern-{}", NONCE_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+// Outgoing commands. Serialised as `{"cmd": ..., "args": {...}}`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "cmd", content = "args", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Command {
+    Authorize {
+        client_id: String,
+        scopes: Vec<String>,
+        prompt: String,
+    },
+    Authenticate {
+        access_token: String,
+    },
+    GetGuilds {},
+    GetChannels {
+        guild_id: String,
+    },
+    GetChannel {
+        channel_id: String,
+    },
+    GetSelectedVoiceChannel {},
+    GetVoiceSettings {},
+    SetVoiceSettings(Value),
+    SelectVoiceChannel {
+        channel_id: String,
+        force: bool,
+    },
+    SetUserVoiceSettings(UserVoiceSettings),
+    Subscribe {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        guild_id: Option<String>,
+    },
+    Unsubscribe {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        channel_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        guild_id: Option<String>,
+    },
+}
+
+// A full outgoing packet
+#[derive(Debug, Clone, Serialize)]
+pub struct Request {
+    #[serde(flatten)]
+    pub command: Command,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub evt: Option<String>,
+    pub nonce: String,
+}
+
+impl Request {
+    pub fn new(command: Command) -> Request {
+        Request {
+            command,
+            evt: None,
+            nonce: next_nonce(),
+        }
+    }
+
+    pub fn subscribe(event: &str, channel_id: Option<String>) -> Request {
+        Request {
+            command: Command::Subscribe {
+                channel_id,
+                guild_id: None,
+            },
+            evt: Some(event.to_string()),
+            nonce: next_nonce(),
+        }
+    }
+
+    // GUILD_STATUS is scoped by guild rather than channel
+    pub fn unsubscribe(event: &str, channel_id: Option<String>) -> Request {
+        Request {
+            command: Command::Unsubscribe {
+                channel_id,
+                guild_id: None,
+            },
+            evt: Some(event.to_string()),
+            nonce: next_nonce(),
+        }
+    }
+
+    pub fn subscribe_guild(event: &str, guild_id: String) -> Request {
+        Request {
+            command: Command::Subscribe {
+                channel_id: None,
+                guild_id: Some(guild_id),
+            },
+            evt: Some(event.to_string()),
+            nonce: next_nonce(),
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let raw = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&raw)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct User {
+    pub id: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub avatar: Option<String>,
+}
+
+// Server & self mute/deaf flags of one member
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoiceFlags {
+    pub mute: bool,
+    pub deaf: bool,
+    pub self_mute: bool,
+    pub self_deaf: bool,
+    pub suppress: bool,
+    #[serde(default)]
+    pub nick: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Pan {
+    pub left: f64,
+    pub right: f64,
+}
+
+// One member of a voice channel, as found in `voice_states` and VOICE_STATE_* events
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoiceStateEntry {
+    #[serde(default)]
+    pub nick: Option<String>,
+    // Muted locally, for us only
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub volume: Option<f64>,
+    #[serde(default)]
+    pub pan: Option<Pan>,
+    pub user: User,
+    pub voice_state: VoiceFlags,
+}
+
+// How another user sounds to us. Unset fields are left alone by Discord
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserVoiceSettings {
+    pub user_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pan: Option<Pan>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+}
+
+impl VoiceStateEntry {
+    // Nickname given inside `voice_state` wins over the outer one
+    pub fn display_nick(&self) -> Option<String> {
+        self.voice_state.nick.clone().or_else(|| self.nick.clone())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelData {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub guild_id: Option<String>,
+    #[serde(rename = "type", default)]
+    pub kind: Option<u32>,
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    #[serde(default)]
+    pub user_limit: Option<u32>,
+    #[serde(default)]
+    pub voice_states: Vec<VoiceStateEntry>,
+}
+
+// Channel as listed by GET_CHANNELS
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelSummary {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelList {
+    pub channels: Vec<ChannelSummary>,
+}
+
+// Readable name of a Discord channel type number
+pub fn channel_kind_name(kind: Option<u32>) -> &'static str {
+    match kind {
+        Some(0) => "text",
+        Some(1) => "dm",
+        Some(2) => "voice",
+        Some(3) => "group_dm",
+        Some(4) => "category",
+        Some(5) => "announcement",
+        Some(13) => "stage",
+        Some(15) => "forum",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Guild {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub icon_url: Option<String>,
+}
+
+impl Guild {
+    // Discord only hands out the full CDN URL, e.g.
+    // `https://cdn.discordapp.com/icons/<id>/<hash>.jpg`. The hash is all we keep
+    pub fn icon_hash(&self) -> Option<String> {
+        let url = self.icon_url.as_ref()?;
+        let file = url.split('?').next()?.rsplit('/').next()?;
+        let hash = file.split('.').next()?;
+        match hash.is_empty() {
+            true => None,
+            false => Some(hash.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuildList {
+    pub guilds: Vec<Guild>,
+}
+
+// Rich content attached to a message, like a link preview
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Embed {
+    // `rich`, `image`, `video`, `link` etc
+    #[serde(default, rename = "type")]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    #[serde(default)]
+    pub filename: String,
+    // Bytes
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+// A text message. MESSAGE_DELETE only fills in `id`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Message {
+    pub id: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub author: Option<User>,
+    // Author's nickname in the guild
+    #[serde(default)]
+    pub nick: Option<String>,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+// MESSAGE_CREATE, MESSAGE_UPDATE and MESSAGE_DELETE
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEventData {
+    pub channel_id: String,
+    pub message: Message,
+}
+
+// GUILD_STATUS, sent once on subscribing and whenever the guild changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuildStatusData {
+    pub guild: Guild,
+    #[serde(default)]
+    pub online: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizeData {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticateData {
+    pub access_token: String,
+    pub user: User,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AudioDevice {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+// One side of the audio setup, input or output
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceSettings {
+    #[serde(default)]
+    pub device_id: String,
+    // Percent. 0 - 100 for input, 0 - 200 for output
+    #[serde(default)]
+    pub volume: f64,
+    #[serde(default)]
+    pub available_devices: Vec<AudioDevice>,
+}
+
+impl DeviceSettings {
+    // The selected device, with its name if Discord listed it
+    pub fn selected(&self) -> AudioDevice {
+        self.available_devices
+            .iter()
+            .find(|device| device.id == self.device_id)
+            .cloned()
+            .unwrap_or_else(|| AudioDevice {
+                id: self.device_id.clone(),
+                name: String::new(),
+            })
+    }
+}
+
+// Voice activity or push to talk, and how either is triggered
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VoiceMode {
+    // `VOICE_ACTIVITY` or `PUSH_TO_TALK`
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    #[serde(default)]
+    pub auto_threshold: bool,
+    #[serde(default)]
+    pub threshold: f64,
+    #[serde(default)]
+    pub shortcut: Value,
+    #[serde(default)]
+    pub delay: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VoiceSettings {
+    #[serde(default)]
+    pub input: DeviceSettings,
+    #[serde(default)]
+    pub output: DeviceSettings,
+    #[serde(default)]
+    pub mode: VoiceMode,
+    #[serde(default)]
+    pub automatic_gain_control: bool,
+    #[serde(default)]
+    pub echo_cancellation: bool,
+    #[serde(default)]
+    pub noise_suppression: bool,
+    #[serde(default)]
+    pub qos: bool,
+    #[serde(default)]
+    pub silence_warning: bool,
+    pub mute: bool,
+    pub deaf: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpeakingData {
+    pub user_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoiceChannelSelectData {
+    #[serde(default)]
+    pub channel_id: Option<String>,
+    #[serde(default)]
+    pub guild_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoiceConnectionStatusData {
+    pub state: String,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub pings: Vec<Ping>,
+    #[serde(default)]
+    pub average_ping: Option<f64>,
+    #[serde(default)]
+    pub last_ping: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ping {
+    pub time: u64,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorData {
+    pub code: i64,
+    pub message: String,
+}
+
+// Replies to a command we sent
+#[derive(Debug, Clone)]
+pub enum Response {
+    Authorize(AuthorizeData),
+    Authenticate(AuthenticateData),
+    GetGuilds(GuildList),
+    GetChannels(ChannelList),
+    GetChannel(ChannelData),
+    GetSelectedVoiceChannel(Option<ChannelData>),
+    GetVoiceSettings(VoiceSettings),
+    SetVoiceSettings(VoiceSettings),
+    SelectVoiceChannel(Option<ChannelData>),
+    SetUserVoiceSettings(UserVoiceSettings),
+    Subscribe,
+    Unsubscribe,
+    Unknown { cmd: String, data: Value },
+}
+
+// Events pushed to us with `"cmd": "DISPATCH"`
+#[derive(Debug, Clone)]
+pub enum Event {
+    Ready,
+    SpeakingStart(SpeakingData),
+    SpeakingStop(SpeakingData),
+    VoiceStateCreate(VoiceStateEntry),
+    VoiceStateUpdate(VoiceStateEntry),
+    VoiceStateDelete(VoiceStateEntry),
+    VoiceChannelSelect(VoiceChannelSelectData),
+    VoiceConnectionStatus(VoiceConnectionStatusData),
+    VoiceSettingsUpdate(VoiceSettings),
+    ChannelUpdate(ChannelData),
+    GuildStatus(GuildStatusData),
+    // We joined a new guild
+    GuildCreate(Guild),
+    MessageCreate(MessageEventData),
+    MessageUpdate(MessageEventData),
+    MessageDelete(MessageEventData),
+    Unknown { evt: String, data: Value },
+}
+
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Response(Response),
+    Event(Event),
+    // Discord rejected the command named by `cmd`
+    Error { cmd: String, error: ErrorData },
+}
+
+// Raw envelope of every incoming packet
+#[derive(Debug, Clone, Deserialize)]
+struct Frame {
+    cmd: String,
+    #[serde(default)]
+    evt: Option<String>,
+    #[serde(default)]
+    data: Value,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    pub nonce: Option<String>,
+    pub payload: Payload,
+}
+
+impl Response {
+    fn decode(cmd: String, data: Value) -> serde_json::Result<Response> {
+        Ok(match cmd.as_str() {
+            "AUTHORIZE" => Response::Authorize(serde_json::from_value(data)?),
+            "AUTHENTICATE" => Response::Authenticate(serde_json::from_value(data)?),
+            "GET_GUILDS" => Response::GetGuilds(serde_json::from_value(data)?),
+            "GET_CHANNELS" => Response::GetChannels(serde_json::from_value(data)?),
+            "GET_CHANNEL" => Response::GetChannel(serde_json::from_value(data)?),
+            "GET_SELECTED_VOICE_CHANNEL" => {
+                Response::GetSelectedVoiceChannel(serde_json::from_value(data)?)
+            }
+            "GET_VOICE_SETTINGS" => Response::GetVoiceSettings(serde_json::from_value(data)?),
+            "SET_VOICE_SETTINGS" => Response::SetVoiceSettings(serde_json::from_value(data)?),
+            "SELECT_VOICE_CHANNEL" => Response::SelectVoiceChannel(serde_json::from_value(data)?),
+            "SET_USER_VOICE_SETTINGS" => {
+                Response::SetUserVoiceSettings(serde_json::from_value(data)?)
+            }
+            "SUBSCRIBE" => Response::Subscribe,
+            "UNSUBSCRIBE" => Response::Unsubscribe,
+            _ => Response::Unknown { cmd, data },
+        })
+    }
+}
+
+impl Event {
+    fn decode(evt: String, data: Value) -> serde_json::Result<Event> {
+        Ok(match evt.as_str() {
+            "READY" => Event::Ready,
+            "SPEAKING_START" => Event::SpeakingStart(serde_json::from_value(data)?),
+            "SPEAKING_STOP" => Event::SpeakingStop(serde_json::from_value(data)?),
+            "VOICE_STATE_CREATE" => Event::VoiceStateCreate(serde_json::from_value(data)?),
+            "VOICE_STATE_UPDATE" => Event::VoiceStateUpdate(serde_json::from_value(data)?),
+            "VOICE_STATE_DELETE" => Event::VoiceStateDelete(serde_json::from_value(data)?),
+            "VOICE_CHANNEL_SELECT" => Event::VoiceChannelSelect(serde_json::from_value(data)?),
+            "VOICE_CONNECTION_STATUS" => {
+                Event::VoiceConnectionStatus(serde_json::from_value(data)?)
+            }
+            "VOICE_SETTINGS_UPDATE" => Event::VoiceSettingsUpdate(serde_json::from_value(data)?),
+            "CHANNEL_UPDATE" => Event::ChannelUpdate(serde_json::from_value(data)?),
+            "GUILD_STATUS" => Event::GuildStatus(serde_json::from_value(data)?),
+            "GUILD_CREATE" => Event::GuildCreate(serde_json::from_value(data)?),
+            "MESSAGE_CREATE" => Event::MessageCreate(serde_json::from_value(data)?),
+            "MESSAGE_UPDATE" => Event::MessageUpdate(serde_json::from_value(data)?),
+            "MESSAGE_DELETE" => Event::MessageDelete(serde_json::from_value(data)?),
+            _ => Event::Unknown { evt, data },
+        })
+    }
+}
+
+impl Incoming {
+    pub fn parse(raw: &str) -> serde_json::Result<Incoming> {
+        let frame: Frame = serde_json::from_str(raw)?;
+        let payload = if frame.evt.as_deref() == Some("ERROR") {
+            Payload::Error {
+                cmd: frame.cmd,
+                error: serde_json::from_value(frame.data)?,
+            }
+        } else if frame.cmd == "DISPATCH" {
+            Payload::Event(Event::decode(frame.evt.unwrap_or_default(), frame.data)?)
+        } else {
+            Payload::Response(Response::decode(frame.cmd, frame.data)?)
+        };
+        Ok(Incoming {
+            nonce: frame.nonce,
+            payload,
+        })
+    }
+}
@@ -0,0 +1,149 @@
+// Raw RPC traffic written to and read back from NDJSON files, one frame per line:
+//   {"ms":1520,"dir":"in","raw":"{\"cmd\":\"DISPATCH\",...}"}
+// `ms` counts from the first frame. Each connection to Discord opens with a `session`
+// frame carrying the URL, so a replay can start over where the live client did.
+// Access tokens and authorization codes are blanked before they hit the disk so
+// recordings can be attached to bug reports
+use clap::{arg, Arg};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+const REDACTED: &str = "REDACTED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    // Discord to us
+    In,
+    // Us to Discord
+    Out,
+    // A new connection starts here
+    Session,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    pub ms: u64,
+    pub dir: Direction,
+    pub raw: String,
+}
+
+// Appends frames to a file as they pass. Cheap to clone, every clone writes to the same file
+#[derive(Clone)]
+pub struct Recorder {
+    // Set by the first frame, so time spent looking for Discord isn't replayed
+    start: Arc<OnceLock<Instant>>,
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    #[allow(dead_code)]
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        Ok(Recorder {
+            start: Arc::new(OnceLock::new()),
+            file: Arc::new(Mutex::new(File::create(path)?)),
+        })
+    }
+
+    // Mark the start of a connection to `url`, everything recorded after belongs to it
+    pub fn session(&self, url: &str) {
+        self.record(Direction::Session, url);
+    }
+
+    // A failed write is logged and the frame dropped, recording is never worth losing the connection over
+    pub fn record(&self, dir: Direction, raw: &str) {
+        let frame = Frame {
+            ms: self.start.get_or_init(Instant::now).elapsed().as_millis() as u64,
+            dir,
+            raw: redact(raw.trim_end()),
+        };
+        let line = match serde_json::to_string(&frame) {
+            Ok(line) => line,
+            Err(_err) => return,
+        };
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = writeln!(file, "{}", line) {
+            eprintln!("Unable to record frame: {}", err);
+        }
+    }
+}
+
+// Blank out anything that would let the reader act as us. Frames without secrets are kept byte for byte
+fn redact(raw: &str) -> String {
+    let mut packet: Value = match serde_json::from_str(raw) {
+        Ok(packet) => packet,
+        Err(_err) => return raw.to_string(),
+    };
+    let mut redacted = false;
+    for (section, field) in [
+        ("args", "access_token"),
+        ("data", "access_token"),
+        ("data", "code"),
+    ] {
+        if let Some(Value::String(secret)) = packet
+            .get_mut(section)
+            .and_then(|section| section.get_mut(field))
+        {
+            *secret = REDACTED.to_string();
+            redacted = true;
+        }
+    }
+    match redacted {
+        true => packet.to_string(),
+        false => raw.to_string(),
+    }
+}
+
+// Read a whole recording, failing on the first line that isn't a frame
+pub fn load(path: &Path) -> io::Result<Vec<Frame>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut frames = vec![];
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame = serde_json::from_str(&line).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", number + 1, err),
+            )
+        })?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+// Split a recording at its session markers, each part holding what Discord sent during
+// one connection. Recordings without markers are a single session
+pub fn sessions(frames: Vec<Frame>) -> Vec<Vec<Frame>> {
+    let mut sessions = vec![];
+    let mut current = vec![];
+    for frame in frames {
+        match frame.dir {
+            Direction::Session => {
+                if !current.is_empty() {
+                    sessions.push(std::mem::take(&mut current));
+                }
+            }
+            Direction::In => current.push(frame),
+            Direction::Out => {}
+        }
+    }
+    if !current.is_empty() {
+        sessions.push(current);
+    }
+    sessions
+}
+
+// `--replay FILE`, understood by every frontend
+pub fn replay_arg() -> Arg<'static> {
+    arg!(--replay <FILE> "Play back a recording from discern-clispam instead of connecting to Discord")
+        .required(false)
+        .global(true)
+}
@@ -1,40 +1,680 @@
 extern crate clap;
 extern crate serde_json;
 use clap::{arg, command, Command};
+use client::{CommandError, DiscordClient};
+use data::{ConnState, SelfUserData, StateEvent, VoiceChannelData};
 use futures::lock::Mutex;
 use futures::stream::StreamExt;
-use serde_json::json;
+use protocol::{
+    channel_kind_name, AudioDevice, ChannelData, ChannelSummary, DeviceSettings, Guild, Pan,
+    VoiceMode, VoiceSettings, VoiceStateEntry,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::exit;
 use std::sync::Arc;
+use tokio::time::{timeout, Duration};
 
+mod client;
 mod core;
 mod data;
+mod endpoint;
 mod macros;
+mod protocol;
+mod recording;
+mod token;
 
-#[tokio::main]
-async fn main() {
-    // Websocket events to main thread
-    let (event_sender, event_recv) = futures::channel::mpsc::channel::<data::ConnState>(10);
-    let event_sender = Arc::new(Mutex::new(event_sender));
-    let event_recv = Arc::new(Mutex::new(event_recv));
+// How long to wait for Discord to be running and accept us
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
-    // Main thread messages to Websocket output
-    let (msg_sender, msg_recv) = futures::channel::mpsc::channel::<String>(10);
-    let msg_sender = Arc::new(Mutex::new(msg_sender));
-    let msg_recv = Arc::new(Mutex::new(msg_recv));
+// Exit codes
+const EXIT_OK: i32 = 0;
+const EXIT_FAILED: i32 = 1; // Discord refused the request
+const EXIT_USAGE: i32 = 2; // Arguments made no sense
+const EXIT_NO_DISCORD: i32 = 3; // Discord not running or not answering
 
-    // Start a thread for connection
-    let connector_event_sender = event_sender.clone();
-    let connector_msg_recv = msg_recv.clone();
-    core::connector(connector_event_sender.clone(), connector_msg_recv.clone()).await;
+// Types to store what the user requested action was
+#[derive(Debug, Clone, Copy)]
+enum AudioAction {
+    True,
+    False,
+    Toggle,
+    Get,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Device {
+    Mute,
+    Deaf,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone)]
+enum DeviceAction {
+    Get,
+    List,
+    Set(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+enum Mode {
+    // Answer one question and exit
+    Query(Action),
+    // Print every change of state until killed
+    Watch,
+    // Print who we are and our own voice state
+    Status,
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    RoomId,
+    RoomName,
+    RoomGuild,
+    RoomUserIds,
+    RoomUserNames,
+    MoveRoom(String),
+    RoomInfo(String),
+    Guilds,
+    Channels(String),
+    Device(Device, AudioAction),
+    AudioDevice(Direction, DeviceAction),
+    VoiceMode(Option<String>),
+    Volume(Direction, Option<u32>),
+    UserVolume(String, Option<u32>),
+    UserMute(String, AudioAction),
+    UserPan(String, Option<Pan>),
+}
+
+// What a subcommand found out, printable in either format
+#[derive(Debug, Clone)]
+enum Output {
+    ChannelId(Option<String>),
+    ChannelName(Option<String>),
+    ChannelGuild(Option<Guild>),
+    UserIds(Vec<VoiceStateEntry>),
+    UserNames(Vec<VoiceStateEntry>),
+    Moved(Option<String>),
+    ChannelInfo(ChannelData),
+    Guilds(Vec<Guild>),
+    Channels(Vec<ChannelSummary>),
+    Device(Device, bool),
+    AudioDevice(Direction, AudioDevice),
+    AudioDevices(Direction, Vec<AudioDevice>),
+    VoiceMode(VoiceMode),
+    Volume(Direction, u32),
+    UserVolume(String, u32),
+    UserMute(String, bool),
+    UserPan(String, Pan),
+    Status(SelfUserData, Option<VoiceChannelData>, Option<String>),
+}
+
+impl Device {
+    fn name(self) -> &'static str {
+        match self {
+            Device::Mute => "mute",
+            Device::Deaf => "deaf",
+        }
+    }
+
+    fn get(self, settings: &VoiceSettings) -> bool {
+        match self {
+            Device::Mute => settings.mute,
+            Device::Deaf => settings.deaf,
+        }
+    }
 
+    async fn set(self, discord: &DiscordClient, value: bool) -> Result<bool, CommandError> {
+        let settings = match self {
+            Device::Mute => discord.set_mute(value).await?,
+            Device::Deaf => discord.set_deaf(value).await?,
+        };
+        Ok(self.get(&settings))
+    }
+}
+
+impl Direction {
+    fn name(self) -> &'static str {
+        match self {
+            Direction::Input => "input",
+            Direction::Output => "output",
+        }
+    }
+
+    fn max_volume(self) -> u32 {
+        match self {
+            Direction::Input => 100,
+            Direction::Output => 200,
+        }
+    }
+
+    fn of(self, settings: VoiceSettings) -> DeviceSettings {
+        match self {
+            Direction::Input => settings.input,
+            Direction::Output => settings.output,
+        }
+    }
+
+    async fn set_device(
+        self,
+        discord: &DiscordClient,
+        device_id: &str,
+    ) -> Result<DeviceSettings, CommandError> {
+        let settings = match self {
+            Direction::Input => discord.set_input_device(device_id).await?,
+            Direction::Output => discord.set_output_device(device_id).await?,
+        };
+        Ok(self.of(settings))
+    }
+
+    async fn set_volume(
+        self,
+        discord: &DiscordClient,
+        volume: u32,
+    ) -> Result<DeviceSettings, CommandError> {
+        let settings = match self {
+            Direction::Input => discord.set_input_volume(volume as f64).await?,
+            Direction::Output => discord.set_output_volume(volume as f64).await?,
+        };
+        Ok(self.of(settings))
+    }
+}
+
+fn user_json(voice_state: &VoiceStateEntry) -> Value {
+    json!({
+        "id": voice_state.user.id,
+        "username": voice_state.user.username,
+        "nick": voice_state.display_nick(),
+    })
+}
+
+impl Output {
+    fn text(&self) -> Vec<String> {
+        match self {
+            Output::ChannelId(id) => vec![id.clone().unwrap_or_else(|| "0".to_string())],
+            Output::ChannelName(name) => vec![name.clone().unwrap_or_default()],
+            Output::ChannelGuild(guild) => guild
+                .iter()
+                .map(|guild| format!("{} {}", guild.id, guild.name))
+                .collect(),
+            Output::UserIds(users) => users.iter().map(|user| user.user.id.clone()).collect(),
+            Output::UserNames(users) => users
+                .iter()
+                .map(|user| user.user.username.clone())
+                .collect(),
+            Output::Moved(_) => vec![],
+            Output::ChannelInfo(channel) => {
+                let mut lines = vec![
+                    format!("id: {}", channel.id),
+                    format!("name: {}", channel.name),
+                    format!("guild_id: {}", channel.guild_id.clone().unwrap_or_default()),
+                    format!("type: {}", channel_kind_name(channel.kind)),
+                ];
+                if let Some(bitrate) = channel.bitrate {
+                    lines.push(format!("bitrate: {}", bitrate));
+                }
+                if let Some(user_limit) = channel.user_limit {
+                    lines.push(format!("user_limit: {}", user_limit));
+                }
+                for voice_state in channel.voice_states.iter() {
+                    lines.push(format!(
+                        "user: {} {}",
+                        voice_state.user.id, voice_state.user.username
+                    ));
+                }
+                lines
+            }
+            Output::Guilds(guilds) => guilds
+                .iter()
+                .map(|guild| format!("{} {}", guild.id, guild.name))
+                .collect(),
+            Output::Channels(channels) => channels
+                .iter()
+                .map(|channel| {
+                    format!(
+                        "{} {} {}",
+                        channel.id,
+                        channel_kind_name(channel.kind),
+                        channel.name
+                    )
+                })
+                .collect(),
+            Output::Device(_, value) => vec![value.to_string()],
+            Output::AudioDevice(_, device) => vec![format!("{} {}", device.id, device.name)],
+            Output::AudioDevices(_, devices) => devices
+                .iter()
+                .map(|device| format!("{} {}", device.id, device.name))
+                .collect(),
+            Output::VoiceMode(mode) => vec![mode.kind.to_lowercase()],
+            Output::Volume(_, volume) => vec![volume.to_string()],
+            Output::UserVolume(_, volume) => vec![volume.to_string()],
+            Output::UserMute(_, mute) => vec![mute.to_string()],
+            Output::UserPan(_, pan) => vec![format!("{} {}", pan.left, pan.right)],
+            Output::Status(self_user, channel, endpoint) => {
+                let mut lines = vec![
+                    format!("id: {}", self_user.user.id),
+                    format!("username: {}", self_user.user.username),
+                    format!("mute: {}", self_user.mute),
+                    format!("deaf: {}", self_user.deaf),
+                    format!("speaking: {}", self_user.speaking),
+                ];
+                if let Some(channel) = channel {
+                    lines.push(format!("channel_id: {}", channel.id));
+                    lines.push(format!("channel: {}", channel.title()));
+                }
+                if let Some(endpoint) = endpoint {
+                    lines.push(format!("endpoint: {}", endpoint));
+                }
+                lines
+            }
+        }
+    }
+
+    fn json(&self) -> Value {
+        match self {
+            Output::ChannelId(id) => json!({ "channel_id": id }),
+            Output::ChannelName(name) => json!({ "channel_name": name }),
+            Output::ChannelGuild(guild) => json!({ "guild": guild }),
+            Output::UserIds(users) | Output::UserNames(users) => {
+                json!({ "users": users.iter().map(user_json).collect::<Vec<Value>>() })
+            }
+            Output::Moved(id) => json!({ "channel_id": id }),
+            Output::ChannelInfo(channel) => json!({
+                "channel": {
+                    "id": channel.id,
+                    "name": channel.name,
+                    "guild_id": channel.guild_id,
+                    "type": channel_kind_name(channel.kind),
+                    "bitrate": channel.bitrate,
+                    "user_limit": channel.user_limit,
+                    "users": channel.voice_states.iter().map(user_json).collect::<Vec<Value>>(),
+                }
+            }),
+            Output::Guilds(guilds) => json!({ "guilds": guilds }),
+            Output::Channels(channels) => json!({
+                "channels": channels
+                    .iter()
+                    .map(|channel| json!({
+                        "id": channel.id,
+                        "name": channel.name,
+                        "type": channel_kind_name(channel.kind),
+                    }))
+                    .collect::<Vec<Value>>()
+            }),
+            Output::Device(device, value) => json!({ device.name(): value }),
+            Output::AudioDevice(direction, device) => json!({ direction.name(): device }),
+            Output::AudioDevices(direction, devices) => {
+                json!({ format!("{}_devices", direction.name()): devices })
+            }
+            Output::VoiceMode(mode) => json!({
+                "mode": mode.kind.to_lowercase(),
+                "auto_threshold": mode.auto_threshold,
+                "threshold": mode.threshold,
+                "delay": mode.delay,
+            }),
+            Output::Volume(direction, volume) => {
+                json!({ format!("{}_volume", direction.name()): volume })
+            }
+            Output::UserVolume(user_id, volume) => {
+                json!({ "user_id": user_id, "volume": volume })
+            }
+            Output::UserMute(user_id, mute) => json!({ "user_id": user_id, "mute": mute }),
+            Output::UserPan(user_id, pan) => json!({ "user_id": user_id, "pan": pan }),
+            Output::Status(self_user, channel, endpoint) => json!({
+                "user": self_user.user,
+                "mute": self_user.mute,
+                "deaf": self_user.deaf,
+                "speaking": self_user.speaking,
+                "channel": channel,
+                "endpoint": endpoint,
+            }),
+        }
+    }
+
+    fn print(&self, format: Format) {
+        match format {
+            Format::Text => {
+                for line in self.text() {
+                    println!("{}", line);
+                }
+            }
+            Format::Json => println!("{}", self.json()),
+        }
+    }
+}
+
+fn error_json(kind: &str, message: String, code: Option<i64>) -> Value {
+    json!({ "error": { "kind": kind, "message": message, "code": code } })
+}
+
+fn fail(format: Format, err: CommandError) -> ! {
+    let (kind, code, exit_code) = match &err {
+        CommandError::Discord(error) => ("discord", Some(error.code), EXIT_FAILED),
+        CommandError::Unexpected(_) => ("unexpected", None, EXIT_FAILED),
+        CommandError::Unreadable(_) => ("unreadable", None, EXIT_FAILED),
+        CommandError::NotFound(_) => ("not_found", None, EXIT_FAILED),
+        CommandError::Timeout => ("timeout", None, EXIT_NO_DISCORD),
+        CommandError::Disconnected => ("disconnected", None, EXIT_NO_DISCORD),
+    };
+    match format {
+        Format::Text => eprintln!("{}", err),
+        Format::Json => println!("{}", error_json(kind, err.to_string(), code)),
+    }
+    exit(exit_code);
+}
+
+fn unknown_args(format: Format) -> ! {
+    match format {
+        Format::Text => eprintln!("Unknown rpc args"),
+        Format::Json => println!(
+            "{}",
+            error_json("usage", "Unknown rpc args".to_string(), None)
+        ),
+    }
+    exit(EXIT_USAGE);
+}
+
+fn audio_action(format: Format, value: Option<&str>) -> AudioAction {
+    match value {
+        Some("true") => AudioAction::True,
+        Some("false") => AudioAction::False,
+        Some("toggle") => AudioAction::Toggle,
+        Some(_) => unknown_args(format),
+        None => AudioAction::Get,
+    }
+}
+
+// Parse a number and check it falls in range, or bail out as a usage error
+fn ranged<T: std::str::FromStr + PartialOrd>(format: Format, value: &str, min: T, max: T) -> T {
+    match value.parse::<T>() {
+        Ok(value) if value >= min && value <= max => value,
+        _ => unknown_args(format),
+    }
+}
+
+fn volume_of(voice_state: &VoiceStateEntry) -> u32 {
+    voice_state
+        .volume
+        .map_or(100, |volume| volume.round() as u32)
+}
+
+fn pan_of(voice_state: &VoiceStateEntry) -> Pan {
+    voice_state.pan.unwrap_or(Pan {
+        left: 1.0,
+        right: 1.0,
+    })
+}
+
+fn device_action(args: &clap::ArgMatches) -> DeviceAction {
+    match args.value_of("set") {
+        Some(device_id) => DeviceAction::Set(device_id.to_string()),
+        None if args.is_present("list") => DeviceAction::List,
+        None => DeviceAction::Get,
+    }
+}
+
+async fn run(discord: &DiscordClient, action: Action) -> Result<Output, CommandError> {
+    discord.wait_ready(CONNECT_TIMEOUT).await?;
+    Ok(match action {
+        Action::RoomId => {
+            let channel = discord.get_selected_voice_channel().await?;
+            Output::ChannelId(channel.map(|channel| channel.id))
+        }
+        Action::RoomName => {
+            let channel = discord.get_selected_voice_channel().await?;
+            Output::ChannelName(channel.map(|channel| channel.name))
+        }
+        Action::RoomGuild => {
+            let channel = discord.get_selected_voice_channel().await?;
+            let guild = match channel.and_then(|channel| channel.guild_id) {
+                Some(guild_id) => discord
+                    .get_guilds()
+                    .await?
+                    .into_iter()
+                    .find(|guild| guild.id == guild_id),
+                None => None,
+            };
+            Output::ChannelGuild(guild)
+        }
+        Action::RoomUserIds => {
+            let channel = discord.get_selected_voice_channel().await?;
+            Output::UserIds(
+                channel
+                    .map(|channel| channel.voice_states)
+                    .unwrap_or_default(),
+            )
+        }
+        Action::RoomUserNames => {
+            let channel = discord.get_selected_voice_channel().await?;
+            Output::UserNames(
+                channel
+                    .map(|channel| channel.voice_states)
+                    .unwrap_or_default(),
+            )
+        }
+        Action::MoveRoom(room_id) => {
+            let channel = discord.select_voice_channel(&room_id).await?;
+            Output::Moved(channel.map(|channel| channel.id))
+        }
+        Action::RoomInfo(room_id) => Output::ChannelInfo(discord.get_channel(&room_id).await?),
+        Action::Guilds => Output::Guilds(discord.get_guilds().await?),
+        Action::Channels(guild_id) => Output::Channels(discord.get_channels(&guild_id).await?),
+        Action::Device(device, audio_action) => {
+            let value = match audio_action {
+                AudioAction::Get => device.get(&discord.get_voice_settings().await?),
+                AudioAction::True => device.set(discord, true).await?,
+                AudioAction::False => device.set(discord, false).await?,
+                AudioAction::Toggle => {
+                    let current = device.get(&discord.get_voice_settings().await?);
+                    device.set(discord, !current).await?
+                }
+            };
+            Output::Device(device, value)
+        }
+        Action::AudioDevice(direction, device_action) => {
+            let current = direction.of(discord.get_voice_settings().await?);
+            match device_action {
+                DeviceAction::Get => Output::AudioDevice(direction, current.selected()),
+                DeviceAction::List => Output::AudioDevices(direction, current.available_devices),
+                DeviceAction::Set(device_id) => {
+                    if !current
+                        .available_devices
+                        .iter()
+                        .any(|device| device.id == device_id)
+                    {
+                        return Err(CommandError::NotFound(format!(
+                            "{} device {}",
+                            direction.name(),
+                            device_id
+                        )));
+                    }
+                    let changed = direction.set_device(discord, &device_id).await?;
+                    Output::AudioDevice(direction, changed.selected())
+                }
+            }
+        }
+        Action::VoiceMode(kind) => {
+            let mut mode = discord.get_voice_settings().await?.mode;
+            if let Some(kind) = kind {
+                // Keep threshold, shortcut etc as they are, only swap the type
+                mode.kind = kind;
+                mode = discord.set_voice_mode(mode).await?.mode;
+            }
+            Output::VoiceMode(mode)
+        }
+        Action::Volume(direction, volume) => {
+            let settings = match volume {
+                Some(volume) => direction.set_volume(discord, volume).await?,
+                None => direction.of(discord.get_voice_settings().await?),
+            };
+            Output::Volume(direction, settings.volume.round() as u32)
+        }
+        Action::UserVolume(user_id, volume) => {
+            let volume = match volume {
+                Some(volume) => {
+                    let settings = discord.set_user_volume(&user_id, volume as f64).await?;
+                    settings
+                        .volume
+                        .map_or(volume, |volume| volume.round() as u32)
+                }
+                None => volume_of(&discord.get_user_voice_state(&user_id).await?),
+            };
+            Output::UserVolume(user_id, volume)
+        }
+        Action::UserMute(user_id, audio_action) => {
+            let mute = match audio_action {
+                AudioAction::Get => discord.get_user_voice_state(&user_id).await?.mute,
+                AudioAction::True => true,
+                AudioAction::False => false,
+                AudioAction::Toggle => !discord.get_user_voice_state(&user_id).await?.mute,
+            };
+            let mute = match audio_action {
+                AudioAction::Get => mute,
+                _ => discord
+                    .set_user_mute(&user_id, mute)
+                    .await?
+                    .mute
+                    .unwrap_or(mute),
+            };
+            Output::UserMute(user_id, mute)
+        }
+        Action::UserPan(user_id, pan) => {
+            let pan = match pan {
+                Some(pan) => discord
+                    .set_user_pan(&user_id, pan)
+                    .await?
+                    .pan
+                    .unwrap_or(pan),
+                None => pan_of(&discord.get_user_voice_state(&user_id).await?),
+            };
+            Output::UserPan(user_id, pan)
+        }
+    })
+}
+
+fn event_text(event: &StateEvent, name: &str) -> String {
+    match event {
+        StateEvent::ChannelChanged { channel_id } => match channel_id {
+            Some(id) => format!("channel {}", id),
+            None => "channel 0".to_string(),
+        },
+        StateEvent::UserJoined { user_id } => format!("joined {} {}", user_id, name),
+        StateEvent::UserLeft { user_id } => format!("left {} {}", user_id, name),
+        StateEvent::SpeakingStarted { user_id } => format!("speaking {} {}", user_id, name),
+        StateEvent::SpeakingStopped { user_id } => format!("silent {} {}", user_id, name),
+        StateEvent::MuteChanged { user_id, mute } => match mute {
+            true => format!("muted {} {}", user_id, name),
+            false => format!("unmuted {} {}", user_id, name),
+        },
+        StateEvent::DeafChanged { user_id, deaf } => match deaf {
+            true => format!("deafened {} {}", user_id, name),
+            false => format!("undeafened {} {}", user_id, name),
+        },
+        StateEvent::Disconnected => "disconnected".to_string(),
+    }
+}
+
+// Our own state, once the connector knows both who we are and our voice settings,
+// and where Discord was found. Speaking is only as fresh as the events seen since connecting
+async fn status(
+    discord: &DiscordClient,
+    mut state_recv: futures::channel::mpsc::Receiver<ConnState>,
+) -> Result<Output, CommandError> {
+    let found = timeout(CONNECT_TIMEOUT, async {
+        while let Some(state) = state_recv.next().await {
+            if let (Some(self_user), Some(_)) = (&state.self_user, &state.voice_settings) {
+                let endpoint = discord.health().borrow().endpoint.clone();
+                return Some(Output::Status(
+                    self_user.clone(),
+                    state.channel.clone(),
+                    endpoint,
+                ));
+            }
+        }
+        None
+    })
+    .await;
+    match found {
+        Ok(Some(output)) => Ok(output),
+        Ok(None) => Err(CommandError::Disconnected),
+        Err(_elapsed) => Err(CommandError::Timeout),
+    }
+}
+
+async fn watch(
+    format: Format,
+    mut events: futures::channel::mpsc::UnboundedReceiver<StateEvent>,
+    mut state_recv: futures::channel::mpsc::Receiver<ConnState>,
+) {
+    // Names of everyone seen so far, so users who just left can still be named
+    let mut names: HashMap<String, String> = HashMap::new();
+    loop {
+        // The state an event comes from is queued before it, so take states first
+        let event = tokio::select! {
+            biased;
+            Some(state) = state_recv.next() => {
+                for id in state.users.keys() {
+                    if let Some(name) = state.display_name(id) {
+                        names.insert(id.clone(), name);
+                    }
+                }
+                continue;
+            }
+            event = events.next() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+        let name = event
+            .user_id()
+            .and_then(|id| names.get(id).cloned())
+            .unwrap_or_default();
+        match format {
+            Format::Text => println!("{}", event_text(&event, &name)),
+            Format::Json => {
+                let mut line = serde_json::to_value(&event).unwrap();
+                if event.user_id().is_some() {
+                    line["name"] = json!(name);
+                }
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
     // Setup Command line args
     let matches = command!()
         .subcommand_required(true)
+        .arg(
+            arg!(-f --format <FORMAT> "Output format. `text` or `json`")
+                .required(false)
+                .global(true)
+                .possible_values(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(recording::replay_arg())
         .subcommand(
             Command::new("channel")
                 .about("Get current channel information")
+                .subcommand_required(true)
                 .subcommand(Command::new("id").about("Get Room ID. None is 0"))
                 .subcommand(Command::new("name").about("Get Room Name"))
+                .subcommand(
+                    Command::new("guild").about("Get ID and name of the guild the room is in"),
+                )
                 .subcommand(
                     Command::new("useridlist").about("Get List of users in room, return IDs"),
                 )
@@ -44,12 +684,34 @@ async fn main() {
                 .subcommand(
                     Command::new("move")
                         .about("Switch to another room by ID")
-                        .arg(arg!([ID] "ID of room to move user to")),
+                        .arg(arg!(<ID> "ID of room to move user to")),
+                )
+                .subcommand(
+                    Command::new("info")
+                        .about("Get details of any room by ID")
+                        .arg(arg!(<ID> "ID of room")),
+                ),
+        )
+        .subcommand(
+            Command::new("guilds")
+                .about("Get information on guilds the user is in")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("List guild IDs and names")),
+        )
+        .subcommand(
+            Command::new("channels")
+                .about("Get information on channels in a guild")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("list")
+                        .about("List channel IDs, types and names")
+                        .arg(arg!(-g --guild <ID> "ID of guild to list channels of")),
                 ),
         )
         .subcommand(
             Command::new("devices")
                 .about("Get audio device information")
+                .subcommand_required(true)
                 .subcommand(
                     Command::new("mute").about("Check mute state of user").arg(
                         arg!(-s --set <VALUE> "Alter mute state. `true` `false` or `toggle`")
@@ -61,246 +723,195 @@ async fn main() {
                         arg!(-s --set <VALUE> "Alter deaf state. `true` `false` or `toggle`")
                             .required(false),
                     ),
+                )
+                .subcommand(
+                    Command::new("input")
+                        .about("Check selected input device")
+                        .arg(arg!(-l --list "List available input devices").conflicts_with("set"))
+                        .arg(arg!(-s --set <ID> "Switch to input device by ID").required(false)),
+                )
+                .subcommand(
+                    Command::new("output")
+                        .about("Check selected output device")
+                        .arg(arg!(-l --list "List available output devices").conflicts_with("set"))
+                        .arg(arg!(-s --set <ID> "Switch to output device by ID").required(false)),
+                )
+                .subcommand(
+                    Command::new("mode")
+                        .about("Check voice activity or push to talk mode")
+                        .arg(
+                            arg!(-s --set <MODE> "Alter mode")
+                                .required(false)
+                                .possible_values(["voice_activity", "push_to_talk"]),
+                        ),
+                )
+                .subcommand(
+                    Command::new("volume")
+                        .about("Check input or output volume")
+                        .arg(arg!(<DIRECTION> "Which volume").possible_values(["input", "output"]))
+                        .arg(
+                            arg!(-s --set <VOLUME> "Alter volume. 0 to 100 for input, 0 to 200 for output")
+                                .required(false),
+                        ),
                 ),
         )
+        .subcommand(
+            Command::new("user")
+                .about("Get or alter how another user sounds to you")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("volume")
+                        .about("Check local volume of a user in the current room")
+                        .arg(arg!(<ID> "ID of user"))
+                        .arg(arg!([VOLUME] "New volume in percent, 0 to 200")),
+                )
+                .subcommand(
+                    Command::new("mute")
+                        .about("Check local mute state of a user in the current room")
+                        .arg(arg!(<ID> "ID of user"))
+                        .arg(
+                            arg!(-s --set <VALUE> "Alter local mute. `true` `false` or `toggle`")
+                                .required(false),
+                        ),
+                )
+                .subcommand(
+                    Command::new("pan")
+                        .about("Check left and right pan of a user in the current room")
+                        .arg(arg!(<ID> "ID of user"))
+                        .arg(
+                            arg!(-l --left <LEVEL> "Left level, 0.0 to 1.0")
+                                .required(false)
+                                .requires("right"),
+                        )
+                        .arg(
+                            arg!(-r --right <LEVEL> "Right level, 0.0 to 1.0")
+                                .required(false)
+                                .requires("left"),
+                        ),
+                ),
+        )
+        .subcommand(Command::new("status").about("Get our own user, mute, deaf and speaking state"))
+        .subcommand(
+            Command::new("watch")
+                .about("Print a line for every change in the current channel until stopped"),
+        )
         .get_matches();
-    // Types to store what the user requested action was
-    #[derive(Debug, Clone)]
-    enum AudioAction {
-        True,
-        False,
-        Toggle,
-        Get,
-    }
-    #[derive(Debug, Clone)]
-    struct Args {
-        get_room_id: bool,
-        get_room_name: bool,
-        get_room_userlist: bool,
-        get_room_idlist: bool,
-        set_room: Option<String>,
-        mute: Option<AudioAction>,
-        deaf: Option<AudioAction>,
-    }
-    let mut user_args = Args {
-        get_room_id: false,
-        get_room_name: false,
-        get_room_userlist: false,
-        get_room_idlist: false,
-        set_room: None,
-        mute: None,
-        deaf: None,
+
+    let format = match matches.value_of("format") {
+        Some("json") => Format::Json,
+        _ => Format::Text,
     };
-    // Decant the args into the store above
-    match matches.subcommand() {
-        Some(("channel", sub)) => match sub.subcommand() {
-            Some(("id", _)) => user_args.get_room_id = true,
-            Some(("name", _)) => user_args.get_room_name = true,
-            Some(("useridlist", _)) => user_args.get_room_idlist = true,
-            Some(("usernamelist", _)) => user_args.get_room_userlist = true,
-            Some(("move", sub)) => {
-                user_args.set_room = Some(sub.value_of("ID").unwrap().to_string());
-            }
-            Some((_, _)) => {
-                println!("Unknown rpc args");
-                std::process::exit(0);
+
+    // Decant the args into an action
+    let mode = match matches.subcommand() {
+        Some(("channel", sub)) => Mode::Query(match sub.subcommand() {
+            Some(("id", _)) => Action::RoomId,
+            Some(("name", _)) => Action::RoomName,
+            Some(("guild", _)) => Action::RoomGuild,
+            Some(("useridlist", _)) => Action::RoomUserIds,
+            Some(("usernamelist", _)) => Action::RoomUserNames,
+            Some(("move", sub)) => Action::MoveRoom(sub.value_of("ID").unwrap().to_string()),
+            Some(("info", sub)) => Action::RoomInfo(sub.value_of("ID").unwrap().to_string()),
+            _ => unknown_args(format),
+        }),
+        Some(("guilds", sub)) => Mode::Query(match sub.subcommand() {
+            Some(("list", _)) => Action::Guilds,
+            _ => unknown_args(format),
+        }),
+        Some(("channels", sub)) => Mode::Query(match sub.subcommand() {
+            Some(("list", args)) => Action::Channels(args.value_of("guild").unwrap().to_string()),
+            _ => unknown_args(format),
+        }),
+        Some(("devices", sub)) => Mode::Query(match sub.subcommand() {
+            Some(("mute", args)) => {
+                Action::Device(Device::Mute, audio_action(format, args.value_of("set")))
             }
-            None => {
-                println!("Unknown rpc args");
-                std::process::exit(0);
+            Some(("deaf", args)) => {
+                Action::Device(Device::Deaf, audio_action(format, args.value_of("set")))
             }
-        },
-        Some(("devices", sub)) => match sub.subcommand() {
-            Some(("mute", args)) => match args.value_of("set") {
-                Some("true") => user_args.mute = Some(AudioAction::True),
-                Some("false") => user_args.mute = Some(AudioAction::False),
-                Some("toggle") => user_args.mute = Some(AudioAction::Toggle),
-                Some(_) => {
-                    println!("Unknown rpc args");
-                    std::process::exit(0);
-                }
-                None => user_args.mute = Some(AudioAction::Get),
-            },
-            Some(("deaf", args)) => match args.value_of("set") {
-                Some("true") => user_args.deaf = Some(AudioAction::True),
-                Some("false") => user_args.deaf = Some(AudioAction::False),
-                Some("toggle") => user_args.deaf = Some(AudioAction::Toggle),
-                Some(_) => {
-                    println!("Unknown rpc args");
-                    std::process::exit(0);
-                }
-                None => user_args.deaf = Some(AudioAction::Get),
-            },
-            Some((_, _)) => {
-                println!("Unknown rpc args");
-                std::process::exit(0);
+            Some(("input", args)) => Action::AudioDevice(Direction::Input, device_action(args)),
+            Some(("output", args)) => Action::AudioDevice(Direction::Output, device_action(args)),
+            Some(("mode", args)) => {
+                Action::VoiceMode(args.value_of("set").map(|mode| mode.to_uppercase()))
             }
-            None => {
-                println!("Unknown rpc args");
-                std::process::exit(0);
+            Some(("volume", args)) => {
+                let direction = match args.value_of("DIRECTION") {
+                    Some("output") => Direction::Output,
+                    _ => Direction::Input,
+                };
+                Action::Volume(
+                    direction,
+                    args.value_of("set")
+                        .map(|volume| ranged(format, volume, 0, direction.max_volume())),
+                )
             }
-        },
-        Some((_, _)) => {
-            println!("Unknown rpc args");
-            std::process::exit(0);
-        }
-        None => {
-            println!("Unknown rpc args");
-            std::process::exit(0);
-        }
-    }
+            _ => unknown_args(format),
+        }),
+        Some(("user", sub)) => Mode::Query(match sub.subcommand() {
+            Some(("volume", args)) => Action::UserVolume(
+                args.value_of("ID").unwrap().to_string(),
+                args.value_of("VOLUME")
+                    .map(|volume| ranged(format, volume, 0, 200)),
+            ),
+            Some(("mute", args)) => Action::UserMute(
+                args.value_of("ID").unwrap().to_string(),
+                audio_action(format, args.value_of("set")),
+            ),
+            Some(("pan", args)) => Action::UserPan(
+                args.value_of("ID").unwrap().to_string(),
+                match (args.value_of("left"), args.value_of("right")) {
+                    (Some(left), Some(right)) => Some(Pan {
+                        left: ranged(format, left, 0.0, 1.0),
+                        right: ranged(format, right, 0.0, 1.0),
+                    }),
+                    _ => None,
+                },
+            ),
+            _ => unknown_args(format),
+        }),
+        Some(("watch", _)) => Mode::Watch,
+        Some(("status", _)) => Mode::Status,
+        _ => unknown_args(format),
+    };
 
-    loop {
-        while let Some(event) = event_recv.lock().await.next().await {
-            println!("{:?}", event);
-            //let data: Value = serde_json::from_str(&event).unwrap();
-            let data = json!([]); // TODO
-                                  // We broke RPC as there is no direct JSON at this point.
-                                  // Need to consider raw-string options again
-            match data["cmd"].as_str() {
-                Some("SELECT_VOICE_CHANNEL") => {
-                    // Successfully Selected a channel. Exit!
-                    std::process::exit(0);
-                }
-                Some("GET_SELECTED_VOICE_CHANNEL") => {
-                    if user_args.get_room_id {
-                        // Print out the channel ID and exit!
-                        match data["data"]["id"].as_str() {
-                            Some(val) => {
-                                println!("{}", val);
-                            }
-                            None => {
-                                println!("0");
-                            }
-                        }
-                        std::process::exit(0);
-                    }
-                    if user_args.get_room_name {
-                        // Print out the channel name and exit!
-                        match data["data"]["name"].as_str() {
-                            Some(val) => {
-                                println!("{}", val);
-                            }
-                            None => {
-                                println!("");
-                            }
-                        }
-                        std::process::exit(0);
-                    }
-                    if user_args.get_room_idlist {
-                        // Print out the user id list and exit!
-                        match data["data"]["voice_states"].as_array() {
-                            Some(array) => {
-                                for user in array {
-                                    println!("{}", user["user"]["id"].as_str().unwrap());
-                                }
-                            }
-                            None => {}
-                        }
-                        std::process::exit(0);
-                    }
-                    if user_args.get_room_userlist {
-                        // Print out the user name list and exit!
-                        match data["data"]["voice_states"].as_array() {
-                            Some(array) => {
-                                for user in array {
-                                    println!("{}", user["user"]["username"].as_str().unwrap());
-                                }
-                            }
-                            None => {}
-                        }
-                        std::process::exit(0);
-                    }
-                }
-                Some("SET_VOICE_SETTINGS") => {
-                    // Successfully set voice settings. Exit!
-                    std::process::exit(0);
-                }
-                Some("GET_VOICE_SETTINGS") => {
-                    // Got current voice settings.
-                    // if we're polling this, return and exit!
-                    // if we're toggling, pass it back inverted
-                    match user_args.mute.clone() {
-                        Some(AudioAction::True) => {}
-                        Some(AudioAction::False) => {}
-                        Some(AudioAction::Toggle) => {
-                            send_mpsc!(
-                                msg_sender,
-                                packet_set_devices!(
-                                    "mute",
-                                    !data["data"]["mute"].as_bool().unwrap(),
-                                    "deadbeef"
-                                )
-                            );
-                        }
-                        Some(AudioAction::Get) => {
-                            println!("{}", data["data"]["mute"].as_bool().unwrap());
-                            std::process::exit(0);
-                        }
-                        None => {}
-                    };
-                    match user_args.deaf.clone() {
-                        Some(AudioAction::True) => {}
-                        Some(AudioAction::False) => {}
-                        Some(AudioAction::Toggle) => {
-                            send_mpsc!(
-                                msg_sender,
-                                packet_set_devices!(
-                                    "deaf",
-                                    !data["data"]["deaf"].as_bool().unwrap(),
-                                    "deadbeef"
-                                )
-                            );
-                        }
-                        Some(AudioAction::Get) => {
-                            println!("{}", data["data"]["deaf"].as_bool().unwrap());
-                            std::process::exit(0);
-                        }
-                        None => {}
-                    }
+    // Websocket events to main thread
+    let (event_sender, event_recv) = futures::channel::mpsc::channel::<ConnState>(10);
+    let event_sender = Arc::new(Mutex::new(event_sender));
+
+    // Start a thread for connection
+    let discord = core::connector_with(
+        event_sender.clone(),
+        core::ConnectorConfig::from_args(&matches),
+    )
+    .await;
+    let events = discord.events();
+
+    match mode {
+        Mode::Query(action) => {
+            // Nobody reads these, don't hold the connector up on them
+            drop(event_recv);
+            drop(events);
+            match run(&discord, action).await {
+                Ok(output) => {
+                    output.print(format);
+                    exit(EXIT_OK);
                 }
-                Some("AUTHENTICATE") => {
-                    // On connection make first call.
-                    match user_args.mute {
-                        Some(AudioAction::True) => {
-                            send_mpsc!(msg_sender, packet_set_devices!("mute", true, "deadbeef"));
-                        }
-                        Some(AudioAction::False) => {
-                            send_mpsc!(msg_sender, packet_set_devices!("mute", false, "deadbeef"));
-                        }
-                        Some(AudioAction::Toggle) => {
-                            send_mpsc!(msg_sender, packet_req_devices!());
-                        }
-                        Some(AudioAction::Get) => {
-                            send_mpsc!(msg_sender, packet_req_devices!());
-                        }
-                        None => {}
-                    }
-                    match user_args.deaf {
-                        Some(AudioAction::True) => {
-                            send_mpsc!(msg_sender, packet_set_devices!("deaf", true, "deadbeef"));
-                        }
-                        Some(AudioAction::False) => {
-                            send_mpsc!(msg_sender, packet_set_devices!("deaf", false, "deadbeef"));
-                        }
-                        Some(AudioAction::Toggle) => {
-                            send_mpsc!(msg_sender, packet_req_devices!());
-                        }
-                        Some(AudioAction::Get) => {
-                            send_mpsc!(msg_sender, packet_req_devices!());
-                        }
-                        None => {}
-                    }
-                    match user_args.set_room {
-                        Some(ref roomid) => {
-                            send_mpsc!(msg_sender, packet_set_channel!(roomid.clone()));
-                        }
-                        None => {}
-                    }
+                Err(err) => fail(format, err),
+            }
+        }
+        Mode::Status => {
+            drop(events);
+            match status(&discord, event_recv).await {
+                Ok(output) => {
+                    output.print(format);
+                    exit(EXIT_OK);
                 }
-                Some(_) => {}
-                None => {}
+                Err(err) => fail(format, err),
             }
         }
+        Mode::Watch => {
+            watch(format, events, event_recv).await;
+            exit(EXIT_OK);
+        }
     }
 }
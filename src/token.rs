@@ -0,0 +1,242 @@
+// Getting and keeping access tokens.
+// The code from AUTHORIZE is swapped for a token by a TokenExchanger, picked from the environment:
+//   DISCERN_TOKEN_URL           endpoint to POST the code to
+//   DISCERN_RPC_CLIENT_SECRET   use a plain OAuth2 exchange with DISCERN_RPC_CLIENT_ID
+//   DISCERN_RPC_REDIRECT_URI    redirect URI registered for that client, if any
+// Tokens are kept between runs, so reconnecting only needs AUTHENTICATE.
+// Stored as JSON `{client_id: access_token}` in $XDG_DATA_HOME/discern/tokens.json,
+// or DISCERN_TOKEN_FILE
+use crate::endpoint::Endpoint;
+use serde_json::{json, Value};
+use std::collections::hash_map::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::pin::Pin;
+
+pub const STREAMKIT_TOKEN_URL: &str = "https://streamkit.discord.com/overlay/token";
+pub const OAUTH2_TOKEN_URL: &str = "https://discord.com/api/oauth2/token";
+
+#[derive(Debug)]
+pub enum TokenError {
+    // Could not reach the endpoint, or it did not answer with JSON
+    Http(reqwest::Error),
+    // Answered, but without an access token
+    NoToken(Value),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Http(err) => write!(f, "Token request failed: {}", err),
+            TokenError::NoToken(reply) => write!(f, "No access token in reply: {}", reply),
+        }
+    }
+}
+
+impl From<reqwest::Error> for TokenError {
+    fn from(err: reqwest::Error) -> TokenError {
+        TokenError::Http(err)
+    }
+}
+
+pub type TokenFuture<'a> = Pin<Box<dyn Future<Output = Result<String, TokenError>> + Send + 'a>>;
+
+// Swaps the code given by AUTHORIZE for an access token
+pub trait TokenExchanger: Send + Sync {
+    fn exchange<'a>(&'a self, code: &'a str) -> TokenFuture<'a>;
+}
+
+fn access_token(reply: Value) -> Result<String, TokenError> {
+    match reply.get("access_token").and_then(|token| token.as_str()) {
+        Some(token) => Ok(token.to_string()),
+        None => Err(TokenError::NoToken(reply)),
+    }
+}
+
+// What the StreamKit overlay does. Only works with the StreamKit client ID
+pub struct StreamkitExchanger {
+    pub url: String,
+}
+
+impl TokenExchanger for StreamkitExchanger {
+    fn exchange<'a>(&'a self, code: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let reply: Value = reqwest::Client::new()
+                .post(&self.url)
+                .json(&json!({ "code": code }))
+                .send()
+                .await?
+                .json()
+                .await?;
+            access_token(reply)
+        })
+    }
+}
+
+// Standard authorization code grant, for a client registered by the user
+pub struct OAuth2Exchanger {
+    pub url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: Option<String>,
+}
+
+impl TokenExchanger for OAuth2Exchanger {
+    fn exchange<'a>(&'a self, code: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move {
+            let mut form = vec![
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+            ];
+            if let Some(redirect_uri) = &self.redirect_uri {
+                form.push(("redirect_uri", redirect_uri.as_str()));
+            }
+            let reply: Value = reqwest::Client::new()
+                .post(&self.url)
+                .form(&form)
+                .send()
+                .await?
+                .json()
+                .await?;
+            access_token(reply)
+        })
+    }
+}
+
+// Hands back the code as the token without asking anyone. Used when replaying
+// a recording, where nothing we send reaches Discord
+pub struct ReplayExchanger;
+
+impl TokenExchanger for ReplayExchanger {
+    fn exchange<'a>(&'a self, code: &'a str) -> TokenFuture<'a> {
+        Box::pin(async move { Ok(code.to_string()) })
+    }
+}
+
+pub fn exchanger_from_env(client_id: &str) -> Box<dyn TokenExchanger> {
+    let url = env::var("DISCERN_TOKEN_URL").ok();
+    match env::var("DISCERN_RPC_CLIENT_SECRET") {
+        Ok(client_secret) => Box::new(OAuth2Exchanger {
+            url: url.unwrap_or_else(|| OAUTH2_TOKEN_URL.to_string()),
+            client_id: client_id.to_string(),
+            client_secret,
+            redirect_uri: env::var("DISCERN_RPC_REDIRECT_URI").ok(),
+        }),
+        Err(_) => Box::new(StreamkitExchanger {
+            url: url.unwrap_or_else(|| STREAMKIT_TOKEN_URL.to_string()),
+        }),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenCache {
+    path: Option<PathBuf>,
+}
+
+fn data_dir() -> Option<PathBuf> {
+    match env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join(".local").join("share")),
+    }
+}
+
+impl Default for TokenCache {
+    fn default() -> TokenCache {
+        TokenCache::new()
+    }
+}
+
+impl TokenCache {
+    pub fn new() -> TokenCache {
+        TokenCache {
+            path: data_dir().map(|dir| dir.join("discern").join("tokens.json")),
+        }
+    }
+
+    // Keeps nothing, every load misses
+    pub fn disabled() -> TokenCache {
+        TokenCache { path: None }
+    }
+
+    pub fn at(path: PathBuf) -> TokenCache {
+        TokenCache { path: Some(path) }
+    }
+
+    // DISCERN_TOKEN_FILE if set, `off` to keep nothing. Otherwise tokens are only kept
+    // for the usual local Discord, so one from a mock on another port or host never
+    // replaces the real one
+    pub fn from_env(endpoint: &Endpoint) -> TokenCache {
+        match env::var("DISCERN_TOKEN_FILE").as_deref() {
+            Ok("off") => TokenCache::disabled(),
+            Ok(path) if !path.is_empty() => TokenCache::at(PathBuf::from(path)),
+            _ if endpoint.is_default_location() => TokenCache::new(),
+            _ => TokenCache::disabled(),
+        }
+    }
+
+    fn read(&self) -> HashMap<String, String> {
+        self.path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|raw| serde_json::from_str(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    fn write(&self, tokens: &HashMap<String, String>) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        if let Some(dir) = path.parent() {
+            if let Err(err) = fs::create_dir_all(dir) {
+                eprintln!("Unable to create {}: {}", dir.display(), err);
+                return;
+            }
+        }
+        let raw = serde_json::to_string(tokens).unwrap();
+        let result = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| {
+                // Mode only applies on creation, tighten files left by anything else
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+                file.write_all(raw.as_bytes())
+            });
+        if let Err(err) = result {
+            eprintln!("Unable to save token to {}: {}", path.display(), err);
+        }
+    }
+
+    pub fn load(&self, client_id: &str) -> Option<String> {
+        self.read().remove(client_id)
+    }
+
+    pub fn store(&self, client_id: &str, token: &str) {
+        let mut tokens = self.read();
+        if tokens.get(client_id).map(|old| old.as_str()) == Some(token) {
+            return;
+        }
+        tokens.insert(client_id.to_string(), token.to_string());
+        self.write(&tokens);
+    }
+
+    // Discord no longer accepts it
+    pub fn forget(&self, client_id: &str) {
+        let mut tokens = self.read();
+        if tokens.remove(client_id).is_some() {
+            self.write(&tokens);
+        }
+    }
+}
@@ -2,11 +2,13 @@ extern crate clap;
 extern crate serde_json;
 use crate::data::calculate_hash;
 use crate::data::ConnState;
+use crate::data::{MemberOrder, OverlayMode};
 use cairo::{
     Antialias, Context, FillRule, FontSlant, FontWeight, ImageSurface, Operator, RectangleInt,
     Region,
 };
 use cairorender::DiscordAvatarRaw;
+use clap::command;
 use futures::lock::Mutex;
 use futures::stream::StreamExt;
 use futures_util::SinkExt;
@@ -20,12 +22,19 @@ use std::io::Cursor;
 use std::sync::Arc;
 
 mod cairorender;
+mod client;
 mod core;
 mod data;
+mod endpoint;
 mod macros;
+mod protocol;
+mod recording;
+mod token;
 
 #[tokio::main]
 async fn main() {
+    let matches = command!().arg(recording::replay_arg()).get_matches();
+
     // Avatar to main thread
     let (avatar_request_sender, avatar_request_recv) =
         futures::channel::mpsc::channel::<ConnState>(10);
@@ -42,21 +51,27 @@ async fn main() {
     let event_sender = Arc::new(Mutex::new(event_sender));
     let event_recv = Arc::new(Mutex::new(event_recv));
 
-    // Main thread messages to Websocket output
-    let (msg_sender, msg_recv) = futures::channel::mpsc::channel::<String>(10);
-    let _msg_sender = Arc::new(Mutex::new(msg_sender));
-    let msg_recv = Arc::new(Mutex::new(msg_recv));
-
     // Start a thread for connection
     let connector_event_sender = event_sender.clone();
-    let connector_msg_recv = msg_recv.clone();
-    core::connector(connector_event_sender.clone(), connector_msg_recv.clone()).await;
+    let discord = core::connector_with(
+        connector_event_sender.clone(),
+        core::ConnectorConfig::from_args(&matches),
+    )
+    .await;
+    let health = discord.health();
 
     // Start a thread for avatars
     cairorender::avatar_downloader(avatar_done_sender, avatar_request_recv).await;
 
     // Avatar grabbing thread
     let state = Arc::new(std::sync::Mutex::new(ConnState::new()));
+    // Connection or voice warning to show, if any
+    let warning = Arc::new(std::sync::Mutex::new(health.borrow().warning()));
+    // Who goes on top
+    let order = MemberOrder::from_env();
+    let mode = OverlayMode::from_env();
+    // How long messages stay up
+    let fade = data::message_fade_from_env();
 
     // GTK/ Glib Main
 
@@ -177,8 +192,10 @@ async fn main() {
         {
             let state = state.clone();
             let avatar_list = avatar_list.clone();
+            let warning = warning.clone();
             window.connect_draw(move |window: &gtk::ApplicationWindow, ctx: &Context| {
-                draw_overlay_gtk!(window, ctx, avatar_list, state);
+                let warning = warning.lock().unwrap().clone();
+                draw_overlay_gtk!(window, ctx, avatar_list, state, mode, order, fade, warning);
 
                 Inhibit(false)
             });
@@ -199,6 +216,42 @@ async fn main() {
         window.show_all();
         let state = state.clone();
 
+        // Poked by the state watcher, as new messages move the next fade
+        let fade_wake = Arc::new(tokio::sync::Notify::new());
+
+        // Redraw while messages fade out, and once more after the last is gone. Sleeps
+        // until the next one starts fading otherwise
+        glib::MainContext::default().spawn_local({
+            let window = window.clone();
+            let state = state.clone();
+            let fade_wake = fade_wake.clone();
+            async move {
+                if fade.is_none() {
+                    return;
+                }
+                let mut was_fading = false;
+                loop {
+                    let now = data::now_ms();
+                    let due = state.lock().unwrap().fade_due(now, fade);
+                    let fading = due == Some(now);
+                    if fading || was_fading {
+                        window.queue_draw();
+                    }
+                    was_fading = fading;
+                    let woken = Box::pin(fade_wake.notified());
+                    match due {
+                        Some(due) => {
+                            let wait = std::time::Duration::from_millis(due - now)
+                                .max(macros::FADE_REDRAW);
+                            let timer = Box::pin(glib::timeout_future(wait));
+                            futures::future::select(timer, woken).await;
+                        }
+                        None => woken.await,
+                    }
+                }
+            }
+        });
+
         // State watcher
         glib::MainContext::default().spawn_local({
             let window = window.clone();
@@ -213,6 +266,23 @@ async fn main() {
                     if calculate_hash(&update_state) != calculate_hash(&last_state) {
                         state.lock().unwrap().replace_self(update_state);
                         window.queue_draw();
+                        fade_wake.notify_one();
+                    }
+                }
+            }
+        });
+
+        // Connection health watcher. Pings change often, only redraw when the warning should change
+        glib::MainContext::default().spawn_local({
+            let window = window.clone();
+            let warning = warning.clone();
+            let mut health = health.clone();
+            async move {
+                while health.changed().await.is_ok() {
+                    let now_warning = health.borrow().warning();
+                    if now_warning != *warning.lock().unwrap() {
+                        *warning.lock().unwrap() = now_warning;
+                        window.queue_draw();
                     }
                 }
             }
@@ -227,12 +297,19 @@ async fn main() {
                 while let Some(event) = avatar_done_recv.lock().await.next().await {
                     match event.raw {
                         Some(raw) => {
-                            let surface = ImageSurface::create_from_png(&mut Cursor::new(raw))
-                                .expect("Error processing user avatar");
+                            // A bad download, e.g. an HTTP error page, is skipped like a missing one
+                            let surface = match ImageSurface::create_from_png(&mut Cursor::new(raw))
+                            {
+                                Ok(surface) => Some(surface),
+                                Err(err) => {
+                                    println!("Unable to decode image {} : {}", event.key, err);
+                                    None
+                                }
+                            };
                             avatar_list
                                 .lock()
                                 .unwrap()
-                                .insert(event.key.clone(), Some(surface));
+                                .insert(event.key.clone(), surface);
                         }
                         None => {
                             println!("Raw is None for user id {}", event.key);
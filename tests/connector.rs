@@ -0,0 +1,590 @@
+// Drive core::connector against the mock Discord and check the ConnStates it emits
+use discern::client::{CommandError, DiscordClient};
+use discern::core::{connector_with, ConnectorConfig, TextChannel};
+use discern::data::{ConnState, MemberOrder, OverlayMode, StateEvent, MESSAGE_LIMIT};
+use discern::endpoint::Endpoint;
+use discern::recording::{self, Direction, Recorder};
+use discern::token::{StreamkitExchanger, TokenCache};
+use discern_mock_discord::{MockDiscord, Scenario, CODE, TOKEN};
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration};
+
+const LIMIT: Duration = Duration::from_secs(5);
+
+fn member(id: &str, username: &str) -> Value {
+    json!({
+        "nick": null,
+        "mute": false,
+        "volume": 100,
+        "user": { "id": id, "username": username, "avatar": null },
+        "voice_state": {
+            "mute": false,
+            "deaf": false,
+            "self_mute": false,
+            "self_deaf": false,
+            "suppress": false,
+        },
+    })
+}
+
+fn in_channel(steps: Value) -> Scenario {
+    serde_json::from_value(json!({
+        "user": { "id": "1", "username": "me", "avatar": null },
+        "channel": {
+            "id": "100",
+            "name": "General",
+            "guild_id": "10",
+            "type": 2,
+            "voice_states": [member("1", "me"), member("2", "friend")],
+        },
+        "steps": steps,
+    }))
+    .unwrap()
+}
+
+// File of our own for each test, so nothing leaks between them
+fn temp_file(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("discern-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn token_file(name: &str) -> PathBuf {
+    temp_file(&format!("{}.json", name))
+}
+
+fn config(mock: &MockDiscord, tokens: TokenCache) -> ConnectorConfig {
+    ConnectorConfig {
+        endpoint: Endpoint {
+            port: Some(mock.port),
+            ..Endpoint::default()
+        },
+        exchanger: Box::new(StreamkitExchanger {
+            url: mock.token_url.clone(),
+        }),
+        tokens,
+        replay: None,
+        recorder: None,
+        text_channel: TextChannel::Voice,
+    }
+}
+
+async fn start(config: ConnectorConfig) -> (DiscordClient, mpsc::Receiver<ConnState>) {
+    let (sender, recv) = mpsc::channel::<ConnState>(100);
+    let discord = connector_with(Arc::new(Mutex::new(sender)), config).await;
+    (discord, recv)
+}
+
+async fn connect(
+    mock: &MockDiscord,
+    tokens: TokenCache,
+) -> (DiscordClient, mpsc::Receiver<ConnState>) {
+    start(config(mock, tokens)).await
+}
+
+// Next state that passes `check`, skipping the ones in between
+async fn wait_for(
+    recv: &mut mpsc::Receiver<ConnState>,
+    check: impl Fn(&ConnState) -> bool,
+) -> ConnState {
+    timeout(LIMIT, async {
+        while let Some(state) = recv.next().await {
+            if check(&state) {
+                return state;
+            }
+        }
+        panic!("Connector stopped sending state");
+    })
+    .await
+    .expect("Timed out waiting for state")
+}
+
+fn talking(state: &ConnState, user_id: &str) -> Option<bool> {
+    state
+        .voice_states
+        .get(user_id)
+        .map(|voice_state| voice_state.talking)
+}
+
+#[tokio::test]
+async fn joins_selected_channel_with_members() {
+    let mock = MockDiscord::start(in_channel(json!([]))).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("joins"))).await;
+
+    let state = wait_for(&mut recv, |state| state.voice_channel.is_some()).await;
+    assert_eq!(state.user_id.as_deref(), Some("1"));
+    assert_eq!(state.voice_channel.as_deref(), Some("100"));
+    assert_eq!(state.users.len(), 2);
+    assert_eq!(state.users["2"].username, "friend");
+    assert_eq!(talking(&state, "2"), Some(false));
+}
+
+#[tokio::test]
+async fn speaking_events_toggle_talking() {
+    let steps = json!([
+        { "evt": "SPEAKING_START", "data": { "user_id": "2" } },
+        { "evt": "SPEAKING_STOP", "data": { "user_id": "2" } },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("speaking"))).await;
+
+    wait_for(&mut recv, |state| talking(state, "2") == Some(false)).await;
+    wait_for(&mut recv, |state| talking(state, "2") == Some(true)).await;
+    let state = wait_for(&mut recv, |state| talking(state, "2") == Some(false)).await;
+    assert_eq!(talking(&state, "1"), Some(false));
+}
+
+fn member_ids(state: &ConnState, order: MemberOrder) -> Vec<&str> {
+    state
+        .members(order)
+        .iter()
+        .map(|member| member.id.as_str())
+        .collect()
+}
+
+#[tokio::test]
+async fn members_keep_a_stable_order() {
+    let steps = json!([
+        { "evt": "SPEAKING_START", "data": { "user_id": "2" } },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("order"))).await;
+
+    let state = wait_for(&mut recv, |state| state.voice_channel.is_some()).await;
+    assert_eq!(member_ids(&state, MemberOrder::Joined), ["1", "2"]);
+    assert_eq!(member_ids(&state, MemberOrder::Discord), ["1", "2"]);
+    assert_eq!(member_ids(&state, MemberOrder::Alphabetical), ["2", "1"]);
+    assert_eq!(member_ids(&state, MemberOrder::RecentSpeaker), ["1", "2"]);
+
+    let state = wait_for(&mut recv, |state| talking(state, "2") == Some(true)).await;
+    assert_eq!(member_ids(&state, MemberOrder::RecentSpeaker), ["2", "1"]);
+    assert_eq!(member_ids(&state, MemberOrder::SelfFirst), ["1", "2"]);
+    assert_eq!(member_ids(&state, MemberOrder::Joined), ["1", "2"]);
+}
+
+#[tokio::test]
+async fn state_events_follow_the_session_in_order() {
+    let steps = json!([
+        { "evt": "SPEAKING_START", "data": { "user_id": "2" } },
+        { "evt": "SPEAKING_STOP", "data": { "user_id": "2" } },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let (discord, _recv) = connect(&mock, TokenCache::at(token_file("events"))).await;
+    let mut events = discord.events();
+
+    let mut seen = vec![];
+    timeout(LIMIT, async {
+        while let Some(event) = events.next().await {
+            let done = matches!(event, StateEvent::SpeakingStopped { .. });
+            seen.push(event);
+            if done {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("Timed out waiting for events");
+
+    let user = |id: &str| id.to_string();
+    assert_eq!(
+        seen[0],
+        StateEvent::ChannelChanged {
+            channel_id: Some("100".to_string())
+        }
+    );
+    assert!(seen.contains(&StateEvent::UserJoined { user_id: user("1") }));
+    assert!(seen.contains(&StateEvent::UserJoined { user_id: user("2") }));
+    assert_eq!(
+        seen[seen.len() - 2..],
+        [
+            StateEvent::SpeakingStarted { user_id: user("2") },
+            StateEvent::SpeakingStopped { user_id: user("2") },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn channel_details_follow_updates() {
+    let steps = json!([
+        {
+            "evt": "CHANNEL_UPDATE",
+            "data": { "id": "100", "name": "Lounge", "guild_id": "10", "type": 2, "bitrate": 96000 },
+        },
+    ]);
+    let mut scenario = in_channel(steps);
+    scenario.guilds = vec![json!({ "id": "10", "name": "My Guild", "icon_url": null })];
+    let mock = MockDiscord::start(scenario).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("details"))).await;
+
+    let state = wait_for(&mut recv, |state| state.channel.is_some()).await;
+    let channel = state.channel.unwrap();
+    assert_eq!(channel.title(), "#General \u{2014} My Guild");
+    assert_eq!(channel.kind, Some(2));
+
+    let state = wait_for(&mut recv, |state| {
+        state
+            .channel
+            .as_ref()
+            .is_some_and(|channel| channel.name == "Lounge")
+    })
+    .await;
+    let channel = state.channel.unwrap();
+    assert_eq!(channel.guild_name.as_deref(), Some("My Guild"));
+    assert_eq!(channel.bitrate, Some(96000));
+}
+
+#[tokio::test]
+async fn guild_status_updates_name_and_icon() {
+    let steps = json!([
+        {
+            "evt": "GUILD_STATUS",
+            "data": {
+                "guild": {
+                    "id": "10",
+                    "name": "Renamed",
+                    "icon_url": "https://cdn.discordapp.com/icons/10/abc123.jpg",
+                },
+                "online": 3,
+            },
+        },
+    ]);
+    let mut scenario = in_channel(steps);
+    scenario.guilds = vec![json!({ "id": "10", "name": "My Guild", "icon_url": null })];
+    let mock = MockDiscord::start(scenario).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("guilds"))).await;
+
+    let state = wait_for(&mut recv, |state| state.guild().is_some()).await;
+    assert_eq!(state.guilds["10"].name, "My Guild");
+    assert_eq!(state.guilds["10"].icon, None);
+
+    let state = wait_for(&mut recv, |state| {
+        state.guild().is_some_and(|guild| guild.icon.is_some())
+    })
+    .await;
+    let guild = state.guild().unwrap();
+    assert_eq!(guild.icon_key().as_deref(), Some("icons/10/abc123"));
+    assert_eq!(state.channel.unwrap().title(), "#General \u{2014} Renamed");
+}
+
+#[tokio::test]
+async fn self_user_outlives_the_channel() {
+    let steps = json!([
+        { "evt": "SPEAKING_START", "data": { "user_id": "1" } },
+        { "evt": "VOICE_CHANNEL_SELECT", "data": { "channel_id": null }, "channel": null },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("self"))).await;
+
+    let speaking = |state: &ConnState| state.self_user.as_ref().map(|me| me.speaking);
+    let state = wait_for(&mut recv, |state| speaking(state) == Some(true)).await;
+    let me = state.self_user.as_ref().unwrap();
+    assert_eq!(me.user.username, "me");
+    assert!(!me.mute && !me.deaf);
+    let shown: Vec<&str> = state
+        .shown_members(OverlayMode::MeOnly, MemberOrder::Joined)
+        .iter()
+        .map(|member| member.id.as_str())
+        .collect();
+    assert_eq!(shown, ["1"]);
+
+    let state = wait_for(&mut recv, |state| state.voice_channel.is_none()).await;
+    assert_eq!(speaking(&state), Some(false));
+    assert_eq!(state.self_user.unwrap().user.id, "1");
+}
+
+#[tokio::test]
+async fn members_come_and_go() {
+    let steps = json!([
+        { "evt": "VOICE_STATE_CREATE", "data": member("3", "newcomer") },
+        { "evt": "VOICE_STATE_DELETE", "data": member("2", "friend") },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("members"))).await;
+
+    let state = wait_for(&mut recv, |state| state.users.contains_key("3")).await;
+    assert_eq!(member_ids(&state, MemberOrder::Joined), ["1", "2", "3"]);
+    assert_eq!(state.users["3"].username, "newcomer");
+
+    let state = wait_for(&mut recv, |state| !state.users.contains_key("2")).await;
+    assert!(!state.voice_states.contains_key("2"));
+    assert_eq!(member_ids(&state, MemberOrder::Joined), ["1", "3"]);
+    assert_eq!(state.voice_channel.as_deref(), Some("100"));
+}
+
+fn message(evt: &str, channel_id: &str, id: &str, content: &str) -> Value {
+    json!({
+        "evt": evt,
+        "data": {
+            "channel_id": channel_id,
+            "message": {
+                "id": id,
+                "content": content,
+                "author": { "id": "2", "username": "friend", "avatar": null },
+                "nick": "Friend",
+                "embeds": [],
+                "attachments": [{ "id": "9", "filename": "cat.png", "size": 1024 }],
+            },
+        },
+    })
+}
+
+#[tokio::test]
+async fn messages_follow_the_voice_text_channel() {
+    let mut steps = vec![
+        message("MESSAGE_CREATE", "100", "m1", "hello"),
+        message("MESSAGE_CREATE", "999", "elsewhere", "not ours"),
+        message("MESSAGE_UPDATE", "100", "m1", "hello again"),
+        message("MESSAGE_CREATE", "100", "m2", "bye"),
+        message("MESSAGE_DELETE", "100", "m2", ""),
+    ];
+    for n in 0..MESSAGE_LIMIT {
+        steps.push(message("MESSAGE_CREATE", "100", &format!("n{}", n), "spam"));
+    }
+    let mock = MockDiscord::start(in_channel(json!(steps))).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("messages"))).await;
+
+    let state = wait_for(&mut recv, |state| {
+        state
+            .messages
+            .iter()
+            .any(|message| message.content == "hello again")
+    })
+    .await;
+    assert_eq!(state.text_channel.as_deref(), Some("100"));
+    assert_eq!(state.messages.len(), 1);
+    let first = &state.messages[0];
+    assert_eq!(first.summary(), "Friend: hello again [cat.png]");
+    assert_eq!(first.author_id.as_deref(), Some("2"));
+
+    let state = wait_for(&mut recv, |state| {
+        state.messages.iter().all(|message| message.id != "m2") && state.messages.len() == 1
+    })
+    .await;
+    assert_eq!(state.messages[0].id, "m1");
+
+    let state = wait_for(&mut recv, |state| {
+        state
+            .messages
+            .back()
+            .is_some_and(|message| message.id == format!("n{}", MESSAGE_LIMIT - 1))
+    })
+    .await;
+    assert_eq!(state.messages.len(), MESSAGE_LIMIT);
+    assert_eq!(state.messages[0].id, "n0");
+}
+
+#[tokio::test]
+async fn slow_frontend_still_gets_the_latest_state() {
+    let steps: Vec<Value> = (0..20)
+        .map(|n| message("MESSAGE_CREATE", "100", &format!("n{}", n), "spam"))
+        .collect();
+    let mock = MockDiscord::start(in_channel(json!(steps))).await.unwrap();
+    let (sender, mut recv) = mpsc::channel::<ConnState>(0);
+    let config = config(&mock, TokenCache::at(token_file("slow")));
+    let _discord = connector_with(Arc::new(Mutex::new(sender)), config).await;
+
+    // Nothing is read until the whole session is over
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    wait_for(&mut recv, |state| {
+        state
+            .messages
+            .back()
+            .is_some_and(|message| message.id == "n19")
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn leaving_channel_clears_members() {
+    let steps = json!([
+        { "evt": "VOICE_CHANNEL_SELECT", "data": { "channel_id": null }, "channel": null },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("leaving"))).await;
+
+    wait_for(&mut recv, |state| state.voice_channel.is_some()).await;
+    let state = wait_for(&mut recv, |state| state.voice_channel.is_none()).await;
+    assert!(state.channel.is_none());
+    assert!(state.users.is_empty());
+    assert!(state.voice_states.is_empty());
+}
+
+#[tokio::test]
+async fn leaving_channel_unsubscribes_its_messages() {
+    let steps = json!([
+        { "evt": "VOICE_CHANNEL_SELECT", "data": { "channel_id": null }, "channel": null },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let (_discord, mut recv) = connect(&mock, TokenCache::at(token_file("unsubscribe"))).await;
+
+    wait_for(&mut recv, |state| state.text_channel.is_some()).await;
+    wait_for(&mut recv, |state| state.text_channel.is_none()).await;
+    let unsubscribed = timeout(LIMIT, async {
+        loop {
+            let unsubscribed = mock.stats().unsubscribed;
+            if unsubscribed.len() >= 3 {
+                return unsubscribed;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Timed out waiting for UNSUBSCRIBE");
+    let channel = Some("100".to_string());
+    assert_eq!(
+        unsubscribed,
+        [
+            ("MESSAGE_CREATE".to_string(), channel.clone()),
+            ("MESSAGE_UPDATE".to_string(), channel.clone()),
+            ("MESSAGE_DELETE".to_string(), channel),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unreadable_reply_fails_its_request() {
+    let scenario = Scenario {
+        guilds: vec![json!("not a guild")],
+        ..Scenario::default()
+    };
+    let mock = MockDiscord::start(scenario).await.unwrap();
+    let (discord, _recv) = connect(&mock, TokenCache::at(token_file("unreadable"))).await;
+
+    discord.wait_ready(LIMIT).await.unwrap();
+    let result = timeout(Duration::from_secs(1), discord.get_guilds())
+        .await
+        .expect("Request waited for its timeout");
+    assert!(matches!(result, Err(CommandError::Unreadable(_))));
+}
+
+#[tokio::test]
+async fn cached_token_skips_authorize() {
+    let path = token_file("cached");
+    TokenCache::at(path.clone()).store(&Endpoint::default().client_id, TOKEN);
+    let mock = MockDiscord::start(Scenario::default()).await.unwrap();
+    let (discord, _recv) = connect(&mock, TokenCache::at(path)).await;
+
+    discord.wait_ready(LIMIT).await.unwrap();
+    let stats = mock.stats();
+    assert_eq!(stats.authorize, 0);
+    assert_eq!(stats.authenticate, 1);
+    assert_eq!(discord.health().borrow().warning(), None);
+    let endpoint = discord.health().borrow().endpoint.clone().unwrap();
+    assert!(endpoint.starts_with(&format!("{}/", mock.url())));
+}
+
+#[tokio::test]
+async fn rejected_token_falls_back_to_authorize() {
+    let path = token_file("rejected");
+    let client_id = Endpoint::default().client_id;
+    TokenCache::at(path.clone()).store(&client_id, "stale-token");
+    let mock = MockDiscord::start(Scenario::default()).await.unwrap();
+    let (discord, _recv) = connect(&mock, TokenCache::at(path.clone())).await;
+
+    discord.wait_ready(LIMIT).await.unwrap();
+    let stats = mock.stats();
+    assert_eq!(stats.rejected, 1);
+    assert_eq!(stats.authorize, 1);
+    assert_eq!(stats.authenticate, 2);
+    assert_eq!(
+        TokenCache::at(path).load(&client_id).as_deref(),
+        Some(TOKEN)
+    );
+}
+
+#[tokio::test]
+async fn refused_authorize_starts_over() {
+    let scenario = Scenario {
+        refuse_authorize: true,
+        ..Scenario::default()
+    };
+    let mock = MockDiscord::start(scenario).await.unwrap();
+    let (_discord, _recv) = connect(&mock, TokenCache::at(token_file("refused"))).await;
+
+    // Dropped after the refusal and back again once the backoff is over
+    timeout(LIMIT, async {
+        while mock.stats().authorize < 2 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("Connector never asked again");
+    assert_eq!(mock.stats().connections, 2);
+}
+
+#[tokio::test]
+async fn replaying_a_recording_reproduces_the_session() {
+    let steps = json!([
+        { "evt": "SPEAKING_START", "data": { "user_id": "2" } },
+        { "evt": "SPEAKING_STOP", "data": { "user_id": "2" } },
+    ]);
+    let mock = MockDiscord::start(in_channel(steps)).await.unwrap();
+    let path = temp_file("session.ndjson");
+    let mut live = config(&mock, TokenCache::at(token_file("recorded")));
+    live.recorder = Some(Recorder::create(&path).unwrap());
+    let (_discord, mut recv) = start(live).await;
+    wait_for(&mut recv, |state| talking(state, "2") == Some(true)).await;
+    let recorded = wait_for(&mut recv, |state| talking(state, "2") == Some(false)).await;
+
+    let frames = recording::load(&path).unwrap();
+    assert!(frames.iter().any(|frame| frame.dir == Direction::Out));
+    assert!(frames
+        .iter()
+        .all(|frame| !frame.raw.contains(TOKEN) && !frame.raw.contains(CODE)));
+
+    let (_replay, mut recv) = start(ConnectorConfig::replay(path)).await;
+    wait_for(&mut recv, |state| talking(state, "2") == Some(true)).await;
+    let replayed = wait_for(&mut recv, |state| talking(state, "2") == Some(false)).await;
+    assert_eq!(replayed.voice_channel, recorded.voice_channel);
+    assert_eq!(replayed.channel, recorded.channel);
+    assert_eq!(
+        member_ids(&replayed, MemberOrder::Joined),
+        member_ids(&recorded, MemberOrder::Joined)
+    );
+    assert_eq!(talking(&replayed, "1"), talking(&recorded, "1"));
+    assert_eq!(mock.stats().connections, 1);
+}
+
+#[tokio::test]
+async fn replay_starts_over_with_each_session() {
+    let path = temp_file("sessions.ndjson");
+    let recorder = Recorder::create(&path).unwrap();
+
+    let first = MockDiscord::start(in_channel(json!([]))).await.unwrap();
+    let mut live = config(&first, TokenCache::at(token_file("first_session")));
+    live.recorder = Some(recorder.clone());
+    let (discord, mut recv) = start(live).await;
+    wait_for(&mut recv, |state| state.users.len() == 2).await;
+    discord.wait_ready(LIMIT).await.unwrap();
+    // Let the last replies land before the next session starts
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // Same channel, but the friend has gone while we were away
+    let mut scenario = in_channel(json!([]));
+    scenario.channel.as_mut().unwrap()["voice_states"] = json!([member("1", "me")]);
+    let second = MockDiscord::start(scenario).await.unwrap();
+    let mut live = config(&second, TokenCache::at(token_file("second_session")));
+    live.recorder = Some(recorder);
+    let (_discord, mut recv) = start(live).await;
+    let recorded = wait_for(&mut recv, |state| {
+        state.voice_channel.is_some() && state.users.len() == 1
+    })
+    .await;
+
+    let (_replay, mut recv) = start(ConnectorConfig::replay(path)).await;
+    let replayed = wait_for(&mut recv, |state| {
+        state.voice_channel.is_some() && state.users.len() == 1
+    })
+    .await;
+    assert_eq!(replayed.voice_channel, recorded.voice_channel);
+    assert_eq!(replayed.channel, recorded.channel);
+    assert_eq!(member_ids(&replayed, MemberOrder::Joined), ["1"]);
+    assert_eq!(talking(&replayed, "2"), None);
+}
@@ -0,0 +1,70 @@
+// The JSON form of ConnState, as documented under "State snapshots" in README.md
+use discern::data::{MemberOrder, Snapshot, SnapshotError, SCHEMA_VERSION};
+use serde_json::{json, Value};
+
+fn example() -> Value {
+    json!({
+        "version": 1,
+        "members": ["1", "2"],
+        "user_id": "1",
+        "voice_channel": "100",
+        "users": {
+            "1": { "avatar": null, "id": "1", "username": "me" },
+            "2": { "avatar": "abc", "id": "2", "username": "friend" },
+        },
+        "voice_states": {
+            "1": {
+                "mute": false, "self_mute": true, "deaf": false, "self_deaf": false,
+                "suppress": false, "nick": null, "talking": false, "local_mute": false,
+                "volume": 100, "joined": 1, "position": 0, "last_spoke": 0,
+            },
+            "2": {
+                "mute": false, "self_mute": false, "deaf": false, "self_deaf": false,
+                "suppress": false, "nick": "Friend", "talking": true, "local_mute": false,
+                "volume": 80, "joined": 2, "position": 1, "last_spoke": 3,
+            },
+        },
+        "voice_settings": null,
+    })
+}
+
+#[test]
+fn documented_example_parses() {
+    let snapshot = Snapshot::from_json(&example().to_string()).unwrap();
+    assert_eq!(snapshot.version, SCHEMA_VERSION);
+    let state = &snapshot.state;
+    assert_eq!(state.voice_channel.as_deref(), Some("100"));
+    assert!(state.voice_states["1"].is_muted());
+    assert!(state.voice_states["2"].talking);
+    assert_eq!(
+        state.display_name(&"2".to_string()).as_deref(),
+        Some("Friend")
+    );
+}
+
+#[test]
+fn round_trips_through_json() {
+    let state = Snapshot::from_json(&example().to_string()).unwrap().state;
+    let snapshot = Snapshot::new(state, MemberOrder::RecentSpeaker);
+    assert_eq!(snapshot.members, ["2", "1"]);
+    assert_eq!(Snapshot::from_json(&snapshot.to_json()).unwrap(), snapshot);
+}
+
+#[test]
+fn internal_counter_is_left_out() {
+    let state = Snapshot::from_json(&example().to_string()).unwrap().state;
+    let json: Value =
+        serde_json::from_str(&Snapshot::new(state, MemberOrder::Joined).to_json()).unwrap();
+    assert!(json.get("tick").is_none());
+}
+
+#[test]
+fn newer_schema_is_refused() {
+    let mut newer = example();
+    newer["version"] = json!(SCHEMA_VERSION + 1);
+    newer["voice_states"] = json!([]);
+    match Snapshot::from_json(&newer.to_string()) {
+        Err(SnapshotError::Version(version)) => assert_eq!(version, SCHEMA_VERSION as u64 + 1),
+        other => panic!("Expected a version error, got {:?}", other),
+    }
+}